@@ -1,14 +1,27 @@
 // src/api
-use oauth2::basic::BasicClient;
-use oauth2::{AuthUrl, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, Scope, TokenUrl};
-use reqwest::Client;
-use std::sync::LazyLock;
-use std::time::Duration;
+use crate::config::settings;
+use crate::credentials::{load_credentials, save_credentials, StoredAccount};
+use crate::oauth_redirect::{wait_for_redirect, CALLBACK_PATH};
+use futures::lock::Mutex;
+use oauth2::basic::{BasicClient, BasicTokenResponse};
+use oauth2::reqwest::async_http_client;
+use oauth2::{
+    AuthType, AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, RefreshToken,
+    Scope, TokenResponse, TokenUrl,
+};
+use reqwest::{Client, IntoUrl, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::TcpListener;
+use std::process::Command;
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const USER_AGENT: &str = "ai.openspace.tactic/0.0.1";
-// TODO Config?
-const AUTH0_CLIENT_ID: &str = "B85VbSiRcD92gcDOhgfQG6CPueV2HgwH";
-const AUTH0_DOMAIN: &str = "login.openspace.ai";
+// How long the loopback listener waits for the user to finish logging in
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+// Refresh a little before the access token actually expires
+const EXPIRY_MARGIN_SECS: u64 = 60;
 
 static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(|| {
     Client::builder()
@@ -18,36 +31,689 @@ static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(|| {
         .expect("client")
 });
 
-static OAUTH_CLIENT: LazyLock<BasicClient> = LazyLock::new(|| {
-    BasicClient::new(
-        ClientId::new(AUTH0_CLIENT_ID.to_string()),
-        None,
-        AuthUrl::new("https://{AUTH0_DOMAIN}/authorize".to_string()).expect("auth url"),
-        None,
-    )
-    .set_redirect_uri(RedirectUrl::new("https://openspace.ai".to_string()).expect("redirect url"))
-});
+// The active account, whose access token is attached to every API request
+static SESSION: RwLock<Option<StoredAccount>> = RwLock::new(None);
+
+// Only one refresh at a time; parallel uploads that all get a 401 share it
+static REFRESH_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// Where the PKCE flow sends the user and exchanges the code. Normally built
+/// from the active environment, but can point at a local mock token server.
+#[derive(Debug, Clone)]
+pub struct OAuthEndpoints {
+    pub client_id: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub userinfo_url: String,
+    pub audience: Option<String>,
+    // 0 lets the OS pick a free port
+    pub redirect_port: u16,
+}
+
+impl OAuthEndpoints {
+    pub fn from_settings() -> Self {
+        let api = &settings().api;
+        Self {
+            client_id: api.auth0_client_id.clone(),
+            auth_url: format!("https://{}/authorize", api.auth0_domain),
+            token_url: format!("https://{}/oauth/token", api.auth0_domain),
+            userinfo_url: format!("https://{}/userinfo", api.auth0_domain),
+            audience: api.auth0_audience.clone(),
+            redirect_port: api.auth0_redirect_port,
+        }
+    }
+
+    fn client(&self) -> Result<BasicClient, Box<dyn std::error::Error>> {
+        Ok(BasicClient::new(
+            ClientId::new(self.client_id.clone()),
+            None,
+            AuthUrl::new(self.auth_url.clone())?,
+            Some(TokenUrl::new(self.token_url.clone())?),
+        )
+        // Public client: no secret, client_id goes in the request body
+        .set_auth_type(AuthType::RequestBody))
+    }
+}
+
+/// Tokens returned by the token endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthTokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    // Unix timestamp (seconds)
+    pub expires_at: Option<u64>,
+}
+
+impl AuthTokens {
+    /// `previous_refresh_token` is kept when the server doesn't rotate it
+    fn from_response(
+        token: &BasicTokenResponse,
+        previous_refresh_token: Option<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(Self {
+            access_token: token.access_token().secret().clone(),
+            refresh_token: token
+                .refresh_token()
+                .map(|t| t.secret().clone())
+                .or(previous_refresh_token),
+            expires_at: token.expires_in().map(|d| now + d.as_secs()),
+        })
+    }
+
+    fn is_expiring(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.expires_at.is_some_and(|at| at <= now + EXPIRY_MARGIN_SECS)
+    }
+}
+
+/// HTTP client for the OpenSpace API. Every request it builds carries the
+/// current access token, read when the request is built so replays pick up a
+/// refreshed token.
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    inner: Client,
+}
+
+impl ApiClient {
+    pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        authorize(self.inner.post(url))
+    }
+
+    pub fn put<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        authorize(self.inner.put(url))
+    }
+}
+
+pub fn http_client() -> ApiClient {
+    ApiClient {
+        inner: HTTP_CLIENT.clone(),
+    }
+}
+
+fn authorize(request: RequestBuilder) -> RequestBuilder {
+    match current_session() {
+        Some(tokens) => request.bearer_auth(tokens.access_token),
+        None => request,
+    }
+}
+
+pub fn current_session() -> Option<AuthTokens> {
+    current_account().map(|a| a.tokens)
+}
+
+pub fn current_account() -> Option<StoredAccount> {
+    SESSION.read().ok()?.clone()
+}
+
+fn set_session(account: Option<StoredAccount>) {
+    if let Ok(mut session) = SESSION.write() {
+        *session = account;
+    }
+}
+
+/// Picks up the active account of this environment from the credential store.
+/// Returns its email.
+pub fn restore_session() -> Option<String> {
+    let store = match load_credentials() {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Could not load saved credentials: {}", e);
+            return None;
+        }
+    };
+
+    let account = store.active_account(settings().environment).cloned();
+    let email = account.as_ref().map(|a| a.email.clone());
+    set_session(account);
+
+    email
+}
+
+/// Emails of the accounts saved for this environment
+pub fn saved_accounts() -> Vec<String> {
+    load_credentials()
+        .map(|store| {
+            store
+                .accounts(settings().environment)
+                .into_iter()
+                .map(|a| a.email.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Makes another saved account the active one
+pub fn switch_account(email: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut store = load_credentials()?;
+    store.set_active(settings().environment, email)?;
+    save_credentials(&store)?;
+
+    set_session(store.active_account(settings().environment).cloned());
+    info!("Switched to {}", email);
+
+    Ok(())
+}
+
+/// Forgets the active account's tokens. Another saved account, if any, becomes
+/// active and its email is returned.
+pub fn logout() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let environment = settings().environment;
+    let mut store = load_credentials()?;
+
+    if let Some(account) = current_account() {
+        store.remove(environment, &account.email);
+        info!("Logged out {}", account.email);
+    }
+    save_credentials(&store)?;
+
+    let next = store.active_account(environment).cloned();
+    let email = next.as_ref().map(|a| a.email.clone());
+    set_session(next);
+
+    Ok(email)
+}
+
+fn persist_account(account: &StoredAccount) {
+    let result = load_credentials().and_then(|mut store| {
+        store.upsert(account.clone());
+        save_credentials(&store)
+    });
+
+    if let Err(e) = result {
+        eprintln!("Could not save credentials: {}", e);
+    }
+}
+
+/// Where a refresh gets new tokens from and what it does with them
+struct SessionRefresh<'a> {
+    endpoints: &'a (dyn Fn() -> OAuthEndpoints + Sync),
+    persist: &'a (dyn Fn(&StoredAccount) + Sync),
+}
+
+/// Runs `send`, which must build its request through [`http_client`]. A token
+/// about to expire is refreshed up front; if the server still answers 401 the
+/// token is refreshed and the request replayed once.
+pub async fn send_authorized<F, Fut>(send: F) -> Result<Response, reqwest::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Response, reqwest::Error>>,
+{
+    let refresh = SessionRefresh {
+        endpoints: &OAuthEndpoints::from_settings,
+        persist: &persist_account,
+    };
+    send_authorized_with(&refresh, send).await
+}
+
+async fn send_authorized_with<F, Fut>(refresh: &SessionRefresh<'_>, send: F) -> Result<Response, reqwest::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Response, reqwest::Error>>,
+{
+    if let Some(tokens) = current_session().filter(|t| t.is_expiring()) {
+        if let Err(e) = refresh_session(refresh, &tokens.access_token).await {
+            eprintln!("Failed to refresh access token: {}", e);
+        }
+    }
+
+    let sent_token = current_session().map(|t| t.access_token);
+    let response = send().await?;
+
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    let Some(sent_token) = sent_token else {
+        return Ok(response);
+    };
+
+    // The refresh error isn't Send, so it must be gone before the replay
+    let refreshed = match refresh_session(refresh, &sent_token).await {
+        Ok(refreshed) => refreshed,
+        Err(e) => {
+            eprintln!("Failed to refresh access token: {}", e);
+            false
+        }
+    };
+    if !refreshed {
+        return Ok(response);
+    }
+
+    info!("Access token refreshed, replaying request");
+    send().await
+}
+
+/// Exchanges the refresh token for a new access token, unless another request
+/// already replaced `stale_token` while we waited. Returns whether a newer
+/// token is now available.
+async fn refresh_session(refresh: &SessionRefresh<'_>, stale_token: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let _guard = REFRESH_LOCK.lock().await;
+
+    let Some(mut account) = current_account() else {
+        return Ok(false);
+    };
+    if account.tokens.access_token != stale_token {
+        return Ok(true);
+    }
+    let Some(refresh_token) = account.tokens.refresh_token.clone() else {
+        return Ok(false);
+    };
+
+    let client = (refresh.endpoints)().client()?;
+    let token = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+        .request_async(async_http_client)
+        .await?;
+
+    account.tokens = AuthTokens::from_response(&token, Some(refresh_token))?;
+    (refresh.persist)(&account);
+    set_session(Some(account));
+
+    Ok(true)
+}
+
+/// Runs the OAuth2 authorization code flow with PKCE in the system browser,
+/// then saves the account and makes it the active one. Logging in to an
+/// account that is already saved just replaces its tokens.
+pub async fn login() -> Result<StoredAccount, Box<dyn std::error::Error>> {
+    let endpoints = OAuthEndpoints::from_settings();
+    let tokens = login_with(&endpoints, open_browser).await?;
+    let email = fetch_email(&endpoints, &tokens.access_token).await?;
+
+    let account = StoredAccount {
+        email,
+        environment: settings().environment,
+        tokens,
+    };
+    persist_account(&account);
+    set_session(Some(account.clone()));
+
+    Ok(account)
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+}
+
+// Identifies the account; `sub` is the fallback when no email scope was granted
+async fn fetch_email(endpoints: &OAuthEndpoints, access_token: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let info: UserInfo = HTTP_CLIENT
+        .get(&endpoints.userinfo_url)
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
 
-pub fn http_client() -> Client {
-    HTTP_CLIENT.clone()
+    Ok(info.email.unwrap_or(info.sub))
 }
 
-pub fn login() {
-    // 1) Get the OAuth client
-    let client = OAUTH_CLIENT.clone();
+/// The browser part of [`login`], with the endpoints and the way the authorize
+/// URL gets opened supplied by the caller. The tokens aren't saved.
+pub async fn login_with<F>(endpoints: &OAuthEndpoints, open: F) -> Result<AuthTokens, Box<dyn std::error::Error>>
+where
+    F: FnOnce(&str) -> Result<(), Box<dyn std::error::Error>>,
+{
+    // 1) Listen on loopback for the redirect
+    let listener = TcpListener::bind(("127.0.0.1", endpoints.redirect_port))?;
+    let redirect_url = format!("http://127.0.0.1:{}{}", listener.local_addr()?.port(), CALLBACK_PATH);
 
-    // 2) PKCE
+    // 2) Get the OAuth client
+    let client = endpoints.client()?.set_redirect_uri(RedirectUrl::new(redirect_url)?);
+
+    // 3) PKCE
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-    // 3) Build Auth
-    let (auth_url, _csrf) = client
+    // 4) Build Auth
+    let mut auth_request = client
         .authorize_url(CsrfToken::new_random)
         .set_pkce_challenge(pkce_challenge)
         // Add whatever scopes OpenSpace’s API requires:
         .add_scope(Scope::new("openid".into()))
         .add_scope(Scope::new("profile".into()))
         .add_scope(Scope::new("email".into()))
-        .url();
+        // Needed to get a refresh token back
+        .add_scope(Scope::new("offline_access".into()));
+    if let Some(audience) = &endpoints.audience {
+        auth_request = auth_request.add_extra_param("audience", audience.clone());
+    }
+    let (auth_url, csrf) = auth_request.url();
+
+    // 5) Send the user to the login page
+    info!("Opening browser for login: {}", auth_url);
+    open(auth_url.as_str())?;
+
+    // 6) Wait for the redirect and check the CSRF state
+    let expected_state = csrf.secret().clone();
+    let code = tokio::task::spawn_blocking(move || wait_for_redirect(listener, &expected_state, LOGIN_TIMEOUT))
+        .await?
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+
+    // 7) Exchange the code, proving we started the flow with the PKCE verifier
+    let token = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(async_http_client)
+        .await?;
+
+    let tokens = AuthTokens::from_response(&token, None)?;
+
+    info!(
+        "Logged in (expires at {:?}, refresh token: {})",
+        tokens.expires_at,
+        tokens.refresh_token.is_some()
+    );
+
+    Ok(tokens)
+}
+
+fn open_browser(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let status = match std::env::consts::OS {
+        "macos" => Command::new("open").arg(url).status(),
+        // `start` would treat the & in the query string as a command separator
+        "windows" => Command::new("rundll32")
+            .arg("url.dll,FileProtocolHandler")
+            .arg(url)
+            .status(),
+        _ => Command::new("xdg-open").arg(url).status(),
+    }?;
+
+    if !status.success() {
+        return Err(format!("Could not open browser, visit {} to log in", url).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use oauth2::url::Url;
+    use sha2::{Digest, Sha256};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use crate::config::Environment;
+    use std::net::TcpStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    const TOKEN_RESPONSE: &str =
+        r#"{"access_token":"access-1","token_type":"Bearer","expires_in":3600,"refresh_token":"refresh-1"}"#;
+
+    /// A local HTTP server answering every request with what `respond` returns
+    /// for its headers and body: a status line such as "200 OK" and a JSON body
+    fn mock_server(respond: impl Fn(&str, String) -> (&'static str, String) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let (status, body) = respond(&head, String::from_utf8(body).unwrap());
+                let headers = format!("Content-Type: application/json\r\nContent-Length: {}", body.len());
+                write!(stream, "HTTP/1.1 {}\r\n{}\r\nConnection: close\r\n\r\n{}", status, headers, body).unwrap();
+            }
+        });
+
+        url
+    }
+
+    /// Answers token requests with TOKEN_RESPONSE and hands over their form bodies
+    fn mock_token_endpoint() -> (String, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let url = mock_server(move |_, body| {
+            tx.send(body).unwrap();
+            ("200 OK", TOKEN_RESPONSE.to_string())
+        });
+
+        (format!("{}/oauth/token", url), rx)
+    }
+
+    fn endpoints(token_url: String) -> OAuthEndpoints {
+        OAuthEndpoints {
+            client_id: "test-client".to_string(),
+            auth_url: "https://login.example.com/authorize".to_string(),
+            token_url,
+            userinfo_url: "https://login.example.com/userinfo".to_string(),
+            audience: None,
+            redirect_port: 0,
+        }
+    }
+
+    fn param(url: &Url, name: &str) -> String {
+        url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned()).unwrap_or_default()
+    }
+
+    /// Plays the browser: "logs in" and follows the redirect back to us with
+    /// `state`, or the state from the authorize URL when it is None
+    fn browser(
+        state: Option<&'static str>,
+        authorize_url: mpsc::Sender<Url>,
+    ) -> impl FnOnce(&str) -> Result<(), Box<dyn std::error::Error>> {
+        move |url: &str| {
+            let url = Url::parse(url)?;
+            let redirect = Url::parse(&param(&url, "redirect_uri"))?;
+            let state = state.map(str::to_string).unwrap_or_else(|| param(&url, "state"));
+            authorize_url.send(url)?;
+
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect((redirect.host_str().unwrap(), redirect.port().unwrap())).unwrap();
+                write!(
+                    stream,
+                    "GET {}?code=the-code&state={} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                    redirect.path(),
+                    state
+                )
+                .unwrap();
+                let _ = stream.read_to_end(&mut Vec::new());
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn exchanges_the_code_with_the_pkce_verifier() {
+        let (token_url, token_requests) = mock_token_endpoint();
+        let (tx, authorize_urls) = mpsc::channel();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let tokens = runtime.block_on(login_with(&endpoints(token_url), browser(None, tx))).unwrap();
+
+        assert_eq!(tokens.access_token, "access-1");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("refresh-1"));
+        assert!(tokens.expires_at.is_some());
+
+        let authorize_url = authorize_urls.recv().unwrap();
+        let form = Url::parse(&format!("http://form/?{}", token_requests.recv().unwrap())).unwrap();
+        assert_eq!(param(&form, "grant_type"), "authorization_code");
+        assert_eq!(param(&form, "code"), "the-code");
+        assert_eq!(param(&form, "client_id"), "test-client");
+        assert_eq!(param(&form, "redirect_uri"), param(&authorize_url, "redirect_uri"));
+
+        // The verifier sent with the code is the one the challenge was made from
+        assert_eq!(param(&authorize_url, "code_challenge_method"), "S256");
+        let verifier = param(&form, "code_verifier");
+        assert!(!verifier.is_empty());
+        assert_eq!(
+            URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())),
+            param(&authorize_url, "code_challenge")
+        );
+    }
+
+    #[test]
+    fn rejects_a_redirect_with_another_state() {
+        let (token_url, token_requests) = mock_token_endpoint();
+        let (tx, _authorize_urls) = mpsc::channel();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime.block_on(login_with(&endpoints(token_url), browser(Some("forged"), tx))).unwrap_err();
+
+        assert!(error.to_string().contains("unexpected state"), "{}", error);
+        // The code was never exchanged
+        assert!(token_requests.try_recv().is_err());
+    }
+
+    // The session is process-wide, so the tests that use it take turns
+    static SESSION_TESTS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// An API that takes only refreshed tokens, and a token endpoint handing
+    /// out "fresh-1", "fresh-2", ... Counts the requests each gets.
+    struct MockBackend {
+        api_url: String,
+        token_url: String,
+        api_requests: Arc<AtomicUsize>,
+        token_requests: Arc<AtomicUsize>,
+    }
+
+    impl MockBackend {
+        fn start(api_accepts_fresh_tokens: bool) -> Self {
+            let (api_requests, token_requests) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+            let requests = api_requests.clone();
+            let api_url = mock_server(move |head, _| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                if api_accepts_fresh_tokens && head.to_ascii_lowercase().contains("authorization: bearer fresh-") {
+                    ("200 OK", "{}".to_string())
+                } else {
+                    ("401 Unauthorized", "{}".to_string())
+                }
+            });
+            let requests = token_requests.clone();
+            let token_url = mock_server(move |_, body| {
+                assert!(body.contains("grant_type=refresh_token"), "{}", body);
+                // Slow enough for concurrent callers to queue up behind the refresh
+                thread::sleep(Duration::from_millis(100));
+                let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                let token = format!(r#"{{"access_token":"fresh-{}","token_type":"Bearer","expires_in":3600}}"#, n);
+                ("200 OK", token)
+            });
+
+            Self {
+                api_url,
+                token_url: format!("{}/oauth/token", token_url),
+                api_requests,
+                token_requests,
+            }
+        }
+
+        /// Sends one API request through `send_authorized_with`, returning its
+        /// status and the accounts persisted along the way
+        async fn send(&self) -> (StatusCode, Vec<StoredAccount>) {
+            let persisted = std::sync::Mutex::new(Vec::new());
+            let endpoints = || endpoints(self.token_url.clone());
+            let persist = |account: &StoredAccount| persisted.lock().unwrap().push(account.clone());
+            let refresh = SessionRefresh {
+                endpoints: &endpoints,
+                persist: &persist,
+            };
+
+            let response = send_authorized_with(&refresh, || http_client().post(&self.api_url).send()).await.unwrap();
+            (response.status(), persisted.into_inner().unwrap())
+        }
+    }
+
+    fn sign_in(expires_at: u64) {
+        set_session(Some(StoredAccount {
+            email: "user@example.com".to_string(),
+            environment: Environment::Local,
+            tokens: AuthTokens {
+                access_token: "stale".to_string(),
+                refresh_token: Some("refresh-1".to_string()),
+                expires_at: Some(expires_at),
+            },
+        }));
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn refreshes_an_expiring_token_before_sending() {
+        let _session = SESSION_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = MockBackend::start(true);
+        sign_in(now() + 10);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (status, persisted) = runtime.block_on(backend.send());
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(backend.token_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.api_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(current_session().unwrap().access_token, "fresh-1");
+        // The refresh token wasn't rotated, so the old one is kept
+        assert_eq!(persisted.len(), 1);
+        assert_eq!(persisted[0].tokens.refresh_token.as_deref(), Some("refresh-1"));
+    }
+
+    #[test]
+    fn refreshes_and_replays_once_after_a_401() {
+        let _session = SESSION_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = MockBackend::start(true);
+        sign_in(now() + 3600);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (status, _) = runtime.block_on(backend.send());
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(backend.token_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.api_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn returns_a_second_401_as_is() {
+        let _session = SESSION_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = MockBackend::start(false);
+        sign_in(now() + 3600);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (status, _) = runtime.block_on(backend.send());
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(backend.token_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.api_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn concurrent_401s_share_one_refresh() {
+        let _session = SESSION_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let backend = MockBackend::start(true);
+        sign_in(now() + 3600);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let results = runtime.block_on(futures::future::join_all((0..4).map(|_| backend.send())));
 
-    println!("opening Browser");
+        assert!(results.iter().all(|(status, _)| *status == StatusCode::OK));
+        assert_eq!(backend.token_requests.load(Ordering::SeqCst), 1);
+        // Callers that send after the refresh get through the first time
+        assert!(backend.api_requests.load(Ordering::SeqCst) <= 8);
+        assert_eq!(current_session().unwrap().access_token, "fresh-1");
+    }
 }
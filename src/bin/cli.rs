@@ -0,0 +1,549 @@
+//! Headless `openspace-sync` command, sharing the upload engine with the
+//! desktop app so syncs can be scripted, e.g. over SSH on a site machine.
+
+use openspace_sync::api;
+use openspace_sync::camera_fs::camera::Camera;
+use openspace_sync::camera_fs::camera_finder::scan_for_camera_fs;
+use openspace_sync::camera_fs::hotplug::open_hotplug_source;
+use openspace_sync::config::{check_settings, settings};
+use openspace_sync::device_type::load_device_registry;
+use openspace_sync::openspace::auto_sync::auto_sync;
+use openspace_sync::openspace::event_log::{camera_json, event_json, write_event_log};
+use openspace_sync::openspace::upload_control::UploadControl;
+use openspace_sync::log::{set_log_output, LogOutput};
+use openspace_sync::openspace::upload_all_files::{
+    list_camera_files, upload_all_files, upload_folder, SyncCheck, UploadEvent, UploadEvents,
+};
+use openspace_sync::storage::{clear_synced_files, file_records, file_state, get_storage_dir, FileState};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage: openspace-sync <command> [options]
+
+Commands:
+  detect         List connected cameras
+  list           List the recordings on connected cameras and whether they are synced
+  upload         Upload recordings the server doesn't have yet
+  watch          Keep running and upload from every camera that gets plugged in
+  status         Show the account, environment and local sync state
+  clear-cache    Forget which files are on the server
+
+Options:
+  --device <ID>  Only this camera, can be repeated (list, upload)
+  --verify       Re-check files recorded as synced with the server (upload, watch)
+  --folder <DIR> Upload recordings copied off a camera into DIR instead, with
+                 --device giving the camera's ID if its files don't (upload)
+  --json         Print one JSON object per line instead of text
+  --verbose      Print the upload engine's progress messages to stderr
+  -h, --help     Show this help";
+
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+#[derive(Debug, Default)]
+struct Args {
+    command: String,
+    device_ids: Vec<String>,
+    folder: Option<PathBuf>,
+    verify: bool,
+    json: bool,
+    verbose: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--device" => parsed.device_ids.push(args.next().ok_or("--device needs a device ID")?),
+            "--folder" => parsed.folder = Some(args.next().ok_or("--folder needs a path")?.into()),
+            "--verify" => parsed.verify = true,
+            "--json" => parsed.json = true,
+            "--verbose" => parsed.verbose = true,
+            "-h" | "--help" => parsed.command = "help".to_string(),
+            other if other.starts_with('-') => return Err(format!("Unknown option {}", other)),
+            command if parsed.command.is_empty() => parsed.command = command.to_string(),
+            extra => return Err(format!("Unexpected argument {}", extra)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    set_log_output(if args.verbose { LogOutput::Stderr } else { LogOutput::Off });
+
+    if args.command != "help" {
+        if let Err(e) = check_settings() {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+        if let Err(e) = get_storage_dir().and_then(|dir| load_device_registry(&dir)) {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let result = match args.command.as_str() {
+        "detect" => detect(&args),
+        "list" => list(&args),
+        "upload" => upload(&args),
+        "watch" => watch(&args),
+        "status" => status(&args),
+        "clear-cache" => clear_cache(&args),
+        "help" => {
+            println!("{}", USAGE);
+            Ok(true)
+        }
+        "" => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+        other => {
+            eprintln!("Unknown command {}\n\n{}", other, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Cameras to work on: all detected ones, or those picked with --device
+fn find_cameras(args: &Args) -> Result<Vec<Camera>, Box<dyn std::error::Error>> {
+    let cameras: Vec<Camera> = scan_for_camera_fs()
+        .into_iter()
+        .filter(|c| args.device_ids.is_empty() || args.device_ids.contains(&c.device_id))
+        .collect();
+
+    if let Some(missing) = args.device_ids.iter().find(|id| !cameras.iter().any(|c| &c.device_id == *id)) {
+        return Err(format!("Camera {} is not connected", missing).into());
+    }
+
+    Ok(cameras)
+}
+
+fn detect(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    let cameras = scan_for_camera_fs();
+
+    for camera in &cameras {
+        if args.json {
+            println!("{}", camera_json(camera));
+        } else {
+            let free_space = match (camera.free_space(), camera.capacity()) {
+                (Some(free), Some(capacity)) => format!("{} free of {}", format_bytes(free), format_bytes(capacity)),
+                _ => "size unknown".to_string(),
+            };
+            println!("{}  {}", camera.device_id, camera.name);
+            println!("    {}, {}", camera.fs_strategy, free_space);
+        }
+    }
+
+    if cameras.is_empty() && !args.json {
+        println!("No camera found");
+    }
+
+    Ok(true)
+}
+
+fn list(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    for camera in find_cameras(args)? {
+        let files = list_camera_files(&camera)?;
+        if !args.json {
+            println!("{} ({} files)", camera.device_id, files.len());
+        }
+
+        for media in files {
+            let state = match file_state(&camera.device_id, &media.file) {
+                Some(FileState::Uploaded) => "uploaded",
+                Some(FileState::Skipped) => "on server",
+                Some(FileState::Uploading) => "partially uploaded",
+                None => "new",
+            };
+
+            if args.json {
+                println!(
+                    "{}",
+                    json!({
+                        "device_id": camera.device_id,
+                        "path": media.file.path,
+                        "size": media.file.size,
+                        "content_type": media.content_type,
+                        "state": state,
+                    })
+                );
+            } else {
+                println!("    {:<18} {:>10}  {}", state, format_bytes(media.file.size), media.file.path);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn status(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    api::restore_session();
+    let account = api::current_account().map(|a| a.email);
+
+    // Files per device and state
+    let mut counts: BTreeMap<String, HashMap<FileState, usize>> = BTreeMap::new();
+    for record in file_records() {
+        *counts.entry(record.device_id).or_default().entry(record.state).or_default() += 1;
+    }
+    let count = |states: &HashMap<FileState, usize>, state| states.get(&state).copied().unwrap_or(0);
+
+    if args.json {
+        let devices: Vec<Value> = counts
+            .iter()
+            .map(|(device_id, states)| {
+                json!({
+                    "device_id": device_id,
+                    "uploaded": count(states, FileState::Uploaded),
+                    "on_server": count(states, FileState::Skipped),
+                    "uploading": count(states, FileState::Uploading),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "environment": settings().environment.to_string(),
+                "api_base_url": settings().api.api_base_url,
+                "account": account,
+                "devices": devices,
+            })
+        );
+    } else {
+        println!("Environment: {} ({})", settings().environment, settings().api.api_base_url);
+        println!("Account: {}", account.as_deref().unwrap_or("not logged in"));
+        if counts.is_empty() {
+            println!("No files synced yet");
+        }
+        for (device_id, states) in &counts {
+            println!(
+                "{}: {} uploaded, {} already on server, {} partially uploaded",
+                device_id,
+                count(states, FileState::Uploaded),
+                count(states, FileState::Skipped),
+                count(states, FileState::Uploading),
+            );
+        }
+    }
+
+    Ok(true)
+}
+
+fn clear_cache(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    clear_synced_files()?;
+
+    if args.json {
+        println!("{}", json!({ "cleared": true }));
+    } else {
+        println!("Cache cleared");
+    }
+
+    Ok(true)
+}
+
+fn upload(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    if api::restore_session().is_none() {
+        return Err("Not logged in, log in with the desktop app first".into());
+    }
+
+    let check = if args.verify { SyncCheck::Server } else { SyncCheck::Local };
+    let concurrency = settings().upload.concurrency();
+    let control = UploadControl::default();
+
+    if let Some(folder) = &args.folder {
+        if args.device_ids.len() > 1 {
+            return Err("--folder takes at most one --device".into());
+        }
+        let device_id = args.device_ids.first().map(String::as_str);
+        return run_engine(args, |events| async move {
+            upload_folder(folder, device_id, concurrency, check, events, &control)
+                .await
+                .map_err(|e| e.to_string())
+        });
+    }
+
+    let cameras = find_cameras(args)?;
+    if cameras.is_empty() {
+        return Err("No camera found".into());
+    }
+
+    let device_ids: Vec<String> = cameras.into_iter().map(|c| c.device_id).collect();
+    run_engine(args, |events| async move {
+        upload_all_files(Some(&device_ids), concurrency, check, events, &control)
+            .await
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Runs until killed, so it can be started as a service
+fn watch(args: &Args) -> Result<bool, Box<dyn std::error::Error>> {
+    if api::restore_session().is_none() {
+        return Err("Not logged in, log in with the desktop app first".into());
+    }
+
+    let check = if args.verify { SyncCheck::Server } else { SyncCheck::Local };
+    let concurrency = settings().upload.concurrency();
+
+    if !args.json {
+        println!("Waiting for cameras to be plugged in, press Ctrl-C to stop");
+    }
+
+    run_engine(args, |events| async move {
+        let stop = Arc::new(AtomicBool::new(false));
+        auto_sync(open_hotplug_source(), concurrency, check, events, stop).await;
+        Ok(())
+    })
+}
+
+/// Runs the upload engine, with its events going to the terminal and the
+/// event log. `engine` gets the only `UploadEvents`, so both subscriptions end
+/// when it is done.
+fn run_engine<F, Fut>(args: &Args, engine: F) -> Result<bool, Box<dyn std::error::Error>>
+where
+    F: FnOnce(UploadEvents) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let runtime = tokio::runtime::Runtime::new()?;
+    let events = UploadEvents::default();
+
+    let (result, progress) = runtime.block_on(async {
+        let log = tokio::spawn(write_event_log(events.stream()));
+        let report = report_events(args, events.stream());
+        let (result, progress) = futures::join!(engine(events), report);
+        let _ = log.await;
+        (result, progress)
+    });
+    result?;
+
+    Ok(progress.failed == 0)
+}
+
+/// Prints upload events until the engine is done with them
+async fn report_events(args: &Args, events: impl Stream<Item = UploadEvent>) -> Progress {
+    let mut events = pin!(events);
+    let mut progress = Progress::new(!args.json && std::io::stderr().is_terminal());
+
+    while let Some(event) = events.next().await {
+        if args.json {
+            println!("{}", event_json(&event));
+        } else {
+            progress.handle(&event);
+        }
+        progress.count(&event);
+    }
+    progress.finish(args.json);
+
+    progress
+}
+
+/**
+ * Terminal output for an upload: a line per finished file, and a progress bar
+ * over every file in flight that is redrawn in place when stderr is a terminal
+*/
+#[derive(Default)]
+struct Progress {
+    draw_bar: bool,
+    // (device ID, filename) -> (bytes uploaded, total bytes)
+    files: HashMap<(String, String), (i64, i64)>,
+    completed: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+impl Progress {
+    fn new(draw_bar: bool) -> Self {
+        Self {
+            draw_bar,
+            ..Self::default()
+        }
+    }
+
+    fn count(&mut self, event: &UploadEvent) {
+        match event {
+            UploadEvent::FileCompleted { .. } => self.completed += 1,
+            UploadEvent::FileSkipped { .. } => self.skipped += 1,
+            UploadEvent::FileFailed { .. } | UploadEvent::CaptureIncomplete { .. } => self.failed += 1,
+            _ => {}
+        }
+    }
+
+    fn handle(&mut self, event: &UploadEvent) {
+        match event {
+            UploadEvent::CameraFound(camera) => self.line(format!("Syncing {} ({})", camera.name, camera.device_id)),
+            UploadEvent::FileStarted { device_id, filename, total_bytes } => {
+                self.files.insert((device_id.clone(), filename.clone()), (0, *total_bytes));
+            }
+            UploadEvent::FileProgress {
+                device_id,
+                filename,
+                bytes_uploaded,
+                total_bytes,
+                ..
+            } => {
+                self.files.insert((device_id.clone(), filename.clone()), (*bytes_uploaded, *total_bytes));
+            }
+            UploadEvent::FileRetrying { filename, retries, reason, .. } => {
+                self.line(format!("Retrying {} (attempt {}): {}", filename, retries + 1, reason))
+            }
+            UploadEvent::FileSkipped { filename, .. } => self.line(format!("Skipped   {}", filename)),
+            UploadEvent::FileCompleted { device_id, filename } => {
+                self.files.remove(&(device_id.clone(), filename.clone()));
+                self.line(format!("Uploaded  {}", filename));
+            }
+            UploadEvent::FileFailed { device_id, filename, error } => {
+                self.files.remove(&(device_id.clone(), filename.clone()));
+                self.line(format!("Failed    {}: {}", filename, error));
+            }
+            UploadEvent::FileCancelled { device_id, filename } => {
+                self.files.remove(&(device_id.clone(), filename.clone()));
+                self.line(format!("Cancelled {}", filename));
+            }
+            UploadEvent::CaptureIncomplete { capture_id, missing, .. } => {
+                self.line(format!("Incomplete capture {}, missing {}", capture_id, missing.join(", ")))
+            }
+        }
+        self.draw();
+    }
+
+    // Prints above the progress bar
+    fn line(&self, message: String) {
+        if self.draw_bar {
+            eprint!("\r\x1b[2K");
+        }
+        println!("{}", message);
+    }
+
+    fn draw(&self) {
+        if !self.draw_bar || self.files.is_empty() {
+            return;
+        }
+
+        let (uploaded, total) = self
+            .files
+            .values()
+            .fold((0, 0), |(uploaded, total), (u, t)| (uploaded + u, total + t));
+        let fraction = if total > 0 { uploaded as f64 / total as f64 } else { 0.0 };
+        let filled = (fraction * PROGRESS_BAR_WIDTH as f64).round() as usize;
+
+        eprint!(
+            "\r\x1b[2K[{}{}] {:>3.0}%  {} of {}, {} file(s) in flight",
+            "#".repeat(filled),
+            "-".repeat(PROGRESS_BAR_WIDTH - filled),
+            fraction * 100.0,
+            format_bytes(uploaded.max(0) as u64),
+            format_bytes(total.max(0) as u64),
+            self.files.len(),
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    fn finish(&self, json: bool) {
+        if self.draw_bar {
+            eprint!("\r\x1b[2K");
+        }
+        if json {
+            println!(
+                "{}",
+                json!({
+                    "event": "finished",
+                    "completed": self.completed,
+                    "skipped": self.skipped,
+                    "failed": self.failed,
+                })
+            );
+        } else {
+            println!("{} uploaded, {} skipped, {} failed", self.completed, self.skipped, self.failed);
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        let args = parse(&["upload", "--device", "a", "--device", "b", "--verify", "--json"]).unwrap();
+
+        assert_eq!(args.command, "upload");
+        assert_eq!(args.device_ids, vec!["a", "b"]);
+        assert!(args.verify && args.json && !args.verbose);
+        assert_eq!(args.folder, None);
+    }
+
+    #[test]
+    fn rejects_unknown_flags_and_extra_arguments() {
+        assert_eq!(parse(&["upload", "--force"]).unwrap_err(), "Unknown option --force");
+        assert_eq!(parse(&["upload", "list"]).unwrap_err(), "Unexpected argument list");
+    }
+
+    #[test]
+    fn options_need_their_values() {
+        assert_eq!(parse(&["upload", "--folder"]).unwrap_err(), "--folder needs a path");
+        assert_eq!(parse(&["list", "--device"]).unwrap_err(), "--device needs a device ID");
+        assert_eq!(parse(&["upload", "--folder", "/media/card"]).unwrap().folder, Some(PathBuf::from("/media/card")));
+    }
+
+    #[test]
+    fn help_wins_over_the_command() {
+        assert_eq!(parse(&["--help"]).unwrap().command, "help");
+        assert_eq!(parse(&["upload", "-h"]).unwrap().command, "help");
+        // Nothing given at all
+        assert_eq!(parse(&[]).unwrap().command, "");
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KB");
+        assert_eq!(format_bytes(8 * 1024 * 1024 + 512 * 1024), "8.5 MB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024 * 1024), "5120.0 GB");
+    }
+}
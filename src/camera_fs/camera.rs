@@ -1,10 +1,93 @@
-pub struct Camera{
+use crate::device_type::DeviceType;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A connected camera: what it is, and where its files can be read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    // USB product string, e.g. "Insta360 ONE X2"
     pub name: String,
+    pub device_type: DeviceType,
+    // Normalized, see device_id::normalize_serial
+    pub serial: String,
+    // e.g. "Insta360 OneX2:sn:IXSE42ABCDEF"
+    pub device_id: String,
     pub fs_strategy: FileStrategy,
-    pub resource: String
+    pub volumes: Vec<Volume>,
+}
+
+impl Camera {
+    pub fn new(
+        name: String,
+        device_type: DeviceType,
+        serial: String,
+        fs_strategy: FileStrategy,
+        volumes: Vec<Volume>,
+    ) -> Self {
+        Self {
+            name,
+            device_id: device_type.device_id(&serial),
+            device_type,
+            serial,
+            fs_strategy,
+            volumes,
+        }
+    }
+
+    /// Content type to upload a file with, or `None` when it isn't a recording
+    /// this camera syncs. `path` is relative to the volume root.
+    pub fn content_type_for(&self, path: &str) -> Option<&'static str> {
+        match self.fs_strategy {
+            FileStrategy::Folder => self.device_type.content_type_for_copy(path),
+            FileStrategy::Mount | FileStrategy::Mtp { .. } => self.device_type.content_type_for(path),
+        }
+    }
+
+    /// Total size of all volumes, if every volume reports one
+    pub fn capacity(&self) -> Option<u64> {
+        sum_volumes(&self.volumes, |v| v.capacity)
+    }
+
+    pub fn free_space(&self) -> Option<u64> {
+        sum_volumes(&self.volumes, |v| v.free_space)
+    }
+}
+
+fn sum_volumes(volumes: &[Volume], value: impl Fn(&Volume) -> Option<u64>) -> Option<u64> {
+    if volumes.is_empty() {
+        return None;
+    }
+    volumes.iter().map(value).sum()
+}
+
+/// A storage area on the camera: a mounted partition or an MTP storage
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub name: String,
+    // Only for mass storage volumes
+    pub mount_point: Option<PathBuf>,
+    pub capacity: Option<u64>,
+    pub free_space: Option<u64>,
 }
 
-enum FileStrategy {
-    MOUNT,
-    MTP
-}
\ No newline at end of file
+/// How a camera's files are reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStrategy {
+    // Mass storage, files are read from the mounted volumes
+    Mount,
+    // MTP device at this USB location (stable while it stays on the same port)
+    Mtp { location_id: u64 },
+    // Recordings copied off the card, or a card in a reader; read like a
+    // mounted volume, but in whatever folder layout they are in
+    Folder,
+}
+
+impl fmt::Display for FileStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileStrategy::Mount => write!(f, "USB storage"),
+            FileStrategy::Mtp { .. } => write!(f, "MTP"),
+            FileStrategy::Folder => write!(f, "Folder"),
+        }
+    }
+}
@@ -1,44 +1,269 @@
-use crate::camera_fs::sys_profiler_usb::UsbRoot;
-use crate::device_type::{DeviceType, VendorType};
-use std::path::PathBuf;
+use crate::camera_fs::camera::{Camera, FileStrategy, Volume};
+use crate::camera_fs::device_id::{normalize_serial, resolve_serial, serial_from_file};
+use crate::camera_fs::file_source::{FileSource, MountedSource};
+use crate::camera_fs::sys_profiler_usb::{UsbNode, UsbRoot};
+use crate::camera_fs::sysfs_usb::{read_mountinfo, read_usb_devices, PROC_MOUNTINFO, SYSFS_USB_DEVICES};
+use crate::device_type::DeviceType;
+use futures::executor::block_on;
+use mtp_rs::mtp::MtpDevice;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-pub struct CameraInfo {
-    pub mount_point: PathBuf,
-    pub device_id: String,
+pub fn scan_for_camera_fs() -> Vec<Camera> {
+    let mut cameras = scan_for_mounted_cameras();
+    cameras.extend(scan_for_camera_mtp(&cameras));
+    cameras
 }
 
-pub fn scan_for_camera_fs() -> Option<CameraInfo> {
+/// A folder of recordings to upload as if it were a camera, e.g. footage
+/// copied to a NAS or a card in a reader. The camera model is recognized by the
+/// card layout or the way the camera names its files, never by extension alone.
+/// The device ID is `device_id` when given, which has to be in the model's
+/// format, otherwise built from the serial number the camera wrote into the
+/// files. Folders mixing recordings of several cameras are refused.
+pub fn camera_from_folder(folder: &Path, device_id: Option<&str>) -> Result<Camera, Box<dyn std::error::Error>> {
+    let files = MountedSource::new(folder.to_path_buf()).list_files()?;
+    let device_id = device_id.map(str::trim).filter(|id| !id.is_empty());
+
+    let mut device_types: Vec<DeviceType> = DeviceType::all()
+        .filter(|t| files.iter().any(|f| t.content_type_for_copy(&f.path).is_some()))
+        .collect();
+    if device_types.is_empty() {
+        return Err(format!("No recordings of a supported camera in {}", folder.display()).into());
+    }
+    // Models that record alike are told apart by the device ID
+    if let Some(device_id) = device_id {
+        let candidates = device_types.clone();
+        device_types.retain(|t| device_id_serial(*t, device_id).is_some());
+        if device_types.is_empty() {
+            let examples: Vec<String> = candidates.iter().map(|t| t.device_id("SERIAL")).collect();
+            let expected = examples.join(" or ");
+            return Err(format!("\"{}\" is not a valid device ID, expected e.g. {}", device_id, expected).into());
+        }
+    }
+    let device_type = match device_types.as_slice() {
+        [device_type] => *device_type,
+        _ => {
+            let names: Vec<String> = device_types.iter().map(|t| t.to_string()).collect();
+            return Err(format!(
+                "Can't tell if {} holds {} recordings, enter the camera's device ID",
+                folder.display(),
+                names.join(" or ")
+            )
+            .into());
+        }
+    };
+
+    let mut serials: Vec<String> = files
+        .iter()
+        .filter(|f| device_type.content_type_for_copy(&f.path).is_some())
+        .filter_map(|f| serial_from_file(device_type, &folder.join(&f.path)))
+        .collect();
+    serials.sort();
+    serials.dedup();
+    let serial = match (serials.as_slice(), device_id.and_then(|id| device_id_serial(device_type, id))) {
+        ([_, _, ..], _) => {
+            return Err(format!(
+                "{} holds recordings of several cameras ({}), upload each camera's recordings from a folder of its own",
+                folder.display(),
+                serials.join(", ")
+            )
+            .into())
+        }
+        ([recorded], Some(entered)) if *recorded != entered => {
+            let folder = folder.display();
+            return Err(format!("The recordings in {} are from camera {}, not {}", folder, recorded, entered).into());
+        }
+        (_, Some(entered)) => entered,
+        ([recorded], None) => recorded.clone(),
+        ([], None) => {
+            return Err(format!(
+                "Couldn't find the {} serial number in {}, enter the camera's device ID",
+                device_type,
+                folder.display()
+            )
+            .into())
+        }
+    };
+
+    let name = folder
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| folder.display().to_string());
+    let volumes = vec![Volume {
+        name: name.clone(),
+        mount_point: Some(folder.to_path_buf()),
+        capacity: None,
+        free_space: None,
+    }];
+
+    Ok(Camera::new(name, device_type, serial, FileStrategy::Folder, volumes))
+}
+
+// The serial in a device ID, which has to be in the model's format with the
+// serial already normalized
+fn device_id_serial(device_type: DeviceType, device_id: &str) -> Option<String> {
+    let serial = device_type.serial_from_device_id(device_id)?;
+    normalize_serial(serial).filter(|normalized| normalized == serial)
+}
+
+fn scan_for_mounted_cameras() -> Vec<Camera> {
     let os = std::env::consts::OS;
 
     match os {
         "linux" => {
-            println!("Linux");
+            info!("Linux");
             scan_for_camera_fs_linux()
         }
         "windows" => {
-            println!("Windows");
+            info!("Windows");
             scan_for_camera_fs_windows()
         }
         "macos" => {
-            println!("MacOS");
+            info!("MacOS");
             scan_for_camera_fs_macos()
         }
         _ => panic!("Unsupported OS"),
     }
 }
 
-fn scan_for_camera_fs_linux() -> Option<CameraInfo>{
-    eprintln!("Linux not supported yet");
-    None
+fn is_camera(vendor_id: Option<u16>, product_id: Option<u16>) -> bool {
+    camera_type(vendor_id, product_id).is_some()
+}
+
+fn camera_type(vendor_id: Option<u16>, product_id: Option<u16>) -> Option<DeviceType> {
+    DeviceType::from_product_id(vendor_id?, product_id?)
+}
+
+fn scan_for_camera_fs_linux() -> Vec<Camera> {
+    scan_for_camera_fs_sysfs(Path::new(SYSFS_USB_DEVICES), Path::new(PROC_MOUNTINFO))
+}
+
+/// Linux detection against an arbitrary sysfs USB root and mountinfo file, so it
+/// can be pointed at a fixture tree instead of the live system.
+pub fn scan_for_camera_fs_sysfs(sysfs_root: &Path, mountinfo: &Path) -> Vec<Camera> {
+    let mounts = read_mountinfo(mountinfo);
+    let mut cameras = Vec::new();
+
+    for camera_node in read_usb_devices(sysfs_root)
+        .into_iter()
+        .filter(|n| is_camera(n.vendor_id, n.product_id))
+    {
+        info!(
+            "Found Camera: {}",
+            camera_node.product.as_deref().unwrap_or("unknown")
+        );
+
+        let Some(device_type) = camera_type(camera_node.vendor_id, camera_node.product_id) else {
+            continue;
+        };
+
+        // Every block device (disk or partition) that is mounted somewhere
+        let mut mount_points: Vec<&Path> = camera_node
+            .block_devices
+            .iter()
+            .flat_map(|dev| mounts.iter().filter(move |m| &m.dev == dev))
+            .map(|m| m.mount_point.as_path())
+            .collect();
+        mount_points.sort();
+        mount_points.dedup();
+
+        if mount_points.is_empty() {
+            info!("Camera has no mounted volume");
+            continue;
+        }
+
+        let volumes: Vec<Volume> = mount_points
+            .iter()
+            .map(|mount_point| {
+                info!("Found Volume: {}", mount_point.display());
+                let (capacity, free_space) = volume_size(mount_point);
+                Volume {
+                    name: volume_name(mount_point),
+                    mount_point: Some(mount_point.to_path_buf()),
+                    capacity,
+                    free_space,
+                }
+            })
+            .collect();
+
+        let Some(serial) = mount_points
+            .iter()
+            .find_map(|m| resolve_serial(device_type, camera_node.serial_num.as_deref(), Some(m)))
+        else {
+            eprintln!("Could not determine serial number for {}", device_type);
+            continue;
+        };
+
+        let camera = Camera::new(
+            camera_node.product.clone().unwrap_or_else(|| device_type.to_string()),
+            device_type,
+            serial,
+            FileStrategy::Mount,
+            volumes,
+        );
+        info!("Device ID: {}", camera.device_id);
+        cameras.push(camera);
+    }
+
+    if cameras.is_empty() {
+        info!("No camera found");
+    }
+
+    cameras
+}
+
+fn volume_name(mount_point: &Path) -> String {
+    mount_point
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| mount_point.display().to_string())
 }
 
-fn scan_for_camera_fs_windows() -> Option<CameraInfo> {
+// Returns (capacity, free space) in bytes, as statvfs reports them
+#[cfg(unix)]
+fn volume_size(mount_point: &Path) -> (Option<u64>, Option<u64>) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(mount_point.as_os_str().as_bytes()) else {
+        return (None, None);
+    };
+    // SAFETY: statvfs only writes into the struct it is given, and path is a
+    // valid NUL-terminated string
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return (None, None);
+    }
+
+    // Field widths differ between platforms
+    let block_size = stat.f_frsize as u64;
+    (
+        (stat.f_blocks as u64).checked_mul(block_size),
+        (stat.f_bavail as u64).checked_mul(block_size),
+    )
+}
+
+#[cfg(not(unix))]
+fn volume_size(_mount_point: &Path) -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+fn scan_for_camera_fs_windows() -> Vec<Camera> {
     eprintln!("Windows not supported yet");
-    None
+    Vec::new()
+}
+
+// Cameras are often plugged in through a hub, so walk the whole tree
+fn flatten_usb_nodes<'a>(nodes: &'a [UsbNode], out: &mut Vec<&'a UsbNode>) {
+    for node in nodes {
+        out.push(node);
+        if let Some(children) = &node.items {
+            flatten_usb_nodes(children, out);
+        }
+    }
 }
 
-fn scan_for_camera_fs_macos() -> Option<CameraInfo> {
+fn scan_for_camera_fs_macos() -> Vec<Camera> {
     let out = Command::new("system_profiler")
         .arg("SPUSBDataType")
         .arg("-json")
@@ -48,53 +273,291 @@ fn scan_for_camera_fs_macos() -> Option<CameraInfo> {
     let json_output: String = String::from_utf8_lossy(&out.stdout).to_string();
     let usb_root: UsbRoot = serde_json::from_str(&json_output).unwrap();
 
-    // Get First Camera Node
-    let Some(camera_node) = usb_root
-        .spusb_data_type
-        .iter()
-        .flat_map(|bus| bus.items.iter())
-        .find(|n| {
-            VendorType::from_vendor_id(n.vendor_id.unwrap_or_default()).is_some()
-                && DeviceType::from_product_id(n.product_id.unwrap_or_default()).is_some()
-        })
-    else {
-        println!("No camera found");
-        return None;
+    let mut nodes = Vec::new();
+    for bus in &usb_root.spusb_data_type {
+        flatten_usb_nodes(&bus.items, &mut nodes);
+    }
+
+    let mut cameras = Vec::new();
+
+    for camera_node in nodes.into_iter().filter(|n| is_camera(n.vendor_id, n.product_id)) {
+        info!("Found Camera: {}", camera_node.name);
+
+        let Some(device_type) = camera_type(camera_node.vendor_id, camera_node.product_id) else {
+            continue;
+        };
+
+        // Every Volume with a mount point
+        let volumes: Vec<Volume> = camera_node
+            .media
+            .iter()
+            .flat_map(|m| m.iter())
+            .flat_map(|m| m.volumes.iter())
+            .flat_map(|v| v.iter())
+            .filter_map(|volume| {
+                let m = volume.mount_point.as_deref()?;
+                info!("Found Volume: {}", m);
+                Some(Volume {
+                    name: volume.name.clone(),
+                    mount_point: Some(PathBuf::from(m)),
+                    capacity: volume.size_in_bytes,
+                    free_space: volume.free_space_in_bytes,
+                })
+            })
+            .collect();
+
+        if volumes.is_empty() {
+            info!("Camera has no mounted volume");
+            continue;
+        }
+
+        // Serial from USB, or from the files on one of the volumes
+        let Some(serial) = volumes
+            .iter()
+            .find_map(|v| resolve_serial(device_type, camera_node.serial_num.as_deref(), v.mount_point.as_deref()))
+        else {
+            eprintln!("Could not determine serial number for {}", device_type);
+            continue;
+        };
+
+        let camera = Camera::new(
+            camera_node.name.clone(),
+            device_type,
+            serial,
+            FileStrategy::Mount,
+            volumes,
+        );
+        info!("Device ID: {}", camera.device_id);
+        cameras.push(camera);
+    }
+
+    if cameras.is_empty() {
+        info!("No camera found");
+    }
+
+    cameras
+}
+
+/// Cameras that only offer MTP, like the Theta Z1. Cameras already found as a
+/// mounted volume are left out so their files aren't synced twice.
+fn scan_for_camera_mtp(mounted: &[Camera]) -> Vec<Camera> {
+    let devices = match MtpDevice::list_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Could not list MTP devices: {}", e);
+            return Vec::new();
+        }
     };
 
-    println!("Found Camera: {}", camera_node.name);
+    let mut cameras = Vec::new();
 
-    // Build device ID from device type and serial number
-    let device_type = DeviceType::from_product_id(camera_node.product_id.unwrap_or_default())?;
-    let _serial_num = camera_node.serial_num.as_deref().unwrap_or("unknown");
+    for device in devices {
+        let Some(device_type) = camera_type(Some(device.vendor_id), Some(device.product_id)) else {
+            continue;
+        };
+        info!("Found MTP Camera: {}", device.display());
 
-    // TODO: Derive the actual device ID from the camera's serial number
-    // The serial number format may not match the expected device ID format
-    // For now, using a mock device ID
-    let device_id = "Insta360 OneX2:sn:INSXECAFEBEEF".to_string();
+        let Some(serial) = resolve_serial(device_type, device.serial_number.as_deref(), None) else {
+            eprintln!("Could not determine serial number for {}", device_type);
+            continue;
+        };
 
-    // Get First Volume with a mount point
-    if let Some(mount_point) = camera_node
-        .media
-        .iter()
-        .flat_map(|m| m.iter())
-        .flat_map(|m| m.volumes.iter())
-        .flat_map(|v| v.iter())
-        .find(|v| v.mount_point.is_some())
-        .map(|v| v.mount_point.as_deref())
-    {
-        if let Some(m) = mount_point {
-            println!("Found Volume: {}", m);
-            println!("Device ID: {}", device_id);
+        let camera = Camera::new(
+            device.product.clone().unwrap_or_else(|| device_type.to_string()),
+            device_type,
+            serial,
+            FileStrategy::Mtp {
+                location_id: device.location_id,
+            },
+            mtp_volumes(device.location_id),
+        );
 
-            Some(CameraInfo {
-                mount_point: PathBuf::from(m),
-                device_id,
+        if mounted.iter().any(|c| c.device_id == camera.device_id) {
+            info!("{} is already mounted, not using MTP", camera.device_id);
+            continue;
+        }
+        info!("Device ID: {}", camera.device_id);
+        cameras.push(camera);
+    }
+
+    cameras
+}
+
+// Opens a session just long enough to read the storage sizes
+fn mtp_volumes(location_id: u64) -> Vec<Volume> {
+    let storages = block_on(async {
+        let device = MtpDevice::open_by_location(location_id).await?;
+        let storages = device.storages().await;
+        device.close().await?;
+        storages
+    });
+
+    match storages {
+        Ok(storages) => storages
+            .iter()
+            .map(|s| Volume {
+                name: s.info().description.clone(),
+                mount_point: None,
+                capacity: Some(s.info().max_capacity),
+                free_space: Some(s.info().free_space_bytes),
             })
-        } else {
-            None
+            .collect(),
+        Err(e) => {
+            eprintln!("Could not read MTP storages: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_fs::device_id::test_files::write_insv;
+    use crate::device_type::test_devices;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn temp_folder(files: &[&str]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"data").unwrap();
+        }
+        dir
+    }
+
+    /// A USB device in a fake /sys/bus/usb/devices, with its disk and partition
+    fn usb_device(sysfs: &Path, name: &str, ids: (&str, &str), serial: &str, devs: &[&str]) {
+        let device = sysfs.join(name);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(device.join("idVendor"), format!("{}\n", ids.0)).unwrap();
+        fs::write(device.join("idProduct"), format!("{}\n", ids.1)).unwrap();
+        fs::write(device.join("product"), "Test Camera\n").unwrap();
+        fs::write(device.join("serial"), format!("{}\n", serial)).unwrap();
+
+        // Interfaces have no idVendor and must not show up as devices
+        let mut block = device.join(format!("{}:1.0/host0/target0:0:0/0:0:0:0/block", name));
+        for (i, dev) in devs.iter().enumerate() {
+            block = block.join(if i == 0 { "sdb".to_string() } else { format!("sdb{}", i) });
+            fs::create_dir_all(&block).unwrap();
+            fs::write(block.join("dev"), format!("{}\n", dev)).unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_mounted_cameras_in_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        let sysfs = root.path().join("sys/bus/usb/devices");
+        let card = root.path().join("media/THETA Z1");
+        fs::create_dir_all(&card).unwrap();
+
+        usb_device(&sysfs, "1-2", ("05ca", "036d"), "10010123", &["8:16", "8:17"]);
+        // A mounted USB stick and a camera whose card isn't mounted
+        usb_device(&sysfs, "1-3", ("0781", "5581"), "4C530001", &["8:32", "8:33"]);
+        usb_device(&sysfs, "1-4", ("05ca", "036d"), "10010456", &["8:48", "8:49"]);
+
+        let mountinfo = root.path().join("mountinfo");
+        fs::write(
+            &mountinfo,
+            format!(
+                "22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw\n\
+                 62 28 8:17 / {} rw,nosuid shared:33 - exfat /dev/sdb1 rw\n\
+                 63 28 8:33 / {}/media/stick rw,nosuid shared:34 - vfat /dev/sdc1 rw\n",
+                card.display().to_string().replace(' ', "\\040"),
+                root.path().display(),
+            ),
+        )
+        .unwrap();
+
+        let cameras = scan_for_camera_fs_sysfs(&sysfs, &mountinfo);
+
+        assert_eq!(cameras.len(), 1);
+        assert_eq!(cameras[0].device_type, test_devices::theta_z1());
+        assert_eq!(cameras[0].serial, "10010123");
+        assert_eq!(cameras[0].volumes.len(), 1);
+        assert_eq!(cameras[0].volumes[0].mount_point.as_deref(), Some(card.as_path()));
+        assert_eq!(cameras[0].volumes[0].name, "THETA Z1");
+    }
+
+    #[test]
+    fn reads_volume_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (capacity, free_space) = volume_size(dir.path());
+        let (capacity, free_space) = (capacity.unwrap(), free_space.unwrap());
+        assert!(capacity > 0 && free_space <= capacity);
+        assert_eq!(volume_size(&dir.path().join("missing")), (None, None));
+    }
+
+    #[test]
+    fn folder_camera_uses_given_device_id() {
+        let dir = temp_folder(&["R0010001.JPG", "R0010001.DNG", "notes.txt"]);
+
+        let camera = camera_from_folder(dir.path(), Some(" RICOH THETA Z1:sn:10010123 ")).unwrap();
+
+        assert_eq!(camera.device_type, test_devices::theta_z1());
+        assert_eq!(camera.device_id, "RICOH THETA Z1:sn:10010123");
+        assert_eq!(camera.serial, "10010123");
+        assert_eq!(camera.fs_strategy, FileStrategy::Folder);
+    }
+
+    #[test]
+    fn folder_camera_needs_recordings_and_a_serial() {
+        let empty = temp_folder(&["notes.txt"]);
+        let theta = temp_folder(&["R0010001.JPG"]);
+
+        assert!(camera_from_folder(empty.path(), None).is_err());
+        // Theta files don't carry the serial number
+        assert!(camera_from_folder(theta.path(), None).is_err());
+    }
+
+    #[test]
+    fn folder_camera_needs_camera_recordings() {
+        // A phone's photos and videos have the extensions a Theta records
+        let phone = temp_folder(&["DCIM/Camera/IMG_20240101_120000.jpg", "DCIM/Camera/VID_20240101_120000.mp4"]);
+        let card = temp_folder(&["DCIM/100RICOH/THETA.JPG"]);
+
+        let error = camera_from_folder(phone.path(), Some("RICOH THETA Z1:sn:10010123")).unwrap_err();
+        assert!(error.to_string().starts_with("No recordings"), "{}", error);
+        // The card layout is enough
+        assert!(camera_from_folder(card.path(), Some("RICOH THETA Z1:sn:10010123")).is_ok());
+    }
+
+    #[test]
+    fn folder_camera_validates_the_device_id() {
+        let dir = temp_folder(&["R0010001.JPG"]);
+
+        let device_ids = [
+            "10010123",
+            "Insta360 OneX2:sn:IXSE42ABCDEF",
+            "RICOH THETA Z1:sn:",
+            "RICOH THETA Z1:sn:0000",
+            "RICOH THETA Z1:sn:1001 0123",
+        ];
+        for device_id in device_ids {
+            let error = camera_from_folder(dir.path(), Some(device_id)).unwrap_err();
+            assert!(error.to_string().contains("not a valid device ID"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn folder_camera_reads_the_serial_from_every_recording() {
+        let dir = temp_folder(&[]);
+        write_insv(&dir.path().join("VID_20240101_120000_00_001.insv"), "IXSE42CAFEBEEF");
+        write_insv(&dir.path().join("IMG_20240101_130000_00_002.insp"), "ixse42-cafebeef");
+
+        let camera = camera_from_folder(dir.path(), None).unwrap();
+        assert_eq!(camera.device_type, test_devices::insta360_one_x2());
+        assert_eq!(camera.device_id, "Insta360 OneX2:sn:IXSE42CAFEBEEF");
+
+        let error = camera_from_folder(dir.path(), Some("Insta360 OneX2:sn:IXSE42ABCDEF")).unwrap_err();
+        assert!(error.to_string().contains("not IXSE42ABCDEF"), "{}", error);
+
+        write_insv(&dir.path().join("VID_20240102_120000_00_003.insv"), "IXSE42ABCDEF");
+        for device_id in [None, Some("Insta360 OneX2:sn:IXSE42ABCDEF")] {
+            let error = camera_from_folder(dir.path(), device_id).unwrap_err();
+            assert!(error.to_string().contains("several cameras"), "{}", error);
         }
-    } else {
-        None
     }
 }
@@ -0,0 +1,258 @@
+use crate::device_type::{DeviceType, VendorType};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Serials that USB mass-storage gadgets report when the firmware doesn't fill one in
+const PLACEHOLDER_SERIALS: &[&str] = &[
+    "UNKNOWN",
+    "0123456789",
+    "1234567890",
+    "123456789ABC",
+    "0123456789ABCDEF",
+    "123456789ABCDEF",
+];
+
+// Insta360 files that carry the camera's serial number
+const INSTA360_SERIAL_FILES: &[&str] = &["insv", "insp"];
+
+// Insta360 files end with a metadata trailer terminated by this magic string
+const INSV_TRAILER_MAGIC: &[u8] = b"8db42d694ccc418790edff439fe026bf";
+const INSV_TRAILER_LEN: u64 = 78;
+const INSV_INFO_RECORD: u16 = 0x101;
+const INSV_INFO_SERIAL_TAG: u8 = 0x0a;
+// Record lengths come from the file, so corrupt ones are capped before
+// allocating. The info record holds a few short strings; serials are well
+// under 64 bytes.
+const INSV_INFO_MAX_LEN: u64 = 4096;
+const MAX_SERIAL_LEN: usize = 64;
+
+/// The camera's normalized serial number. The USB serial is used unless it is a
+/// placeholder, in which case the serial is read back from metadata the camera
+/// wrote onto the card, when the card is mounted at `card_root`.
+pub fn resolve_serial(device_type: DeviceType, usb_serial: Option<&str>, card_root: Option<&Path>) -> Option<String> {
+    usb_serial.and_then(normalize_serial).or_else(|| {
+        info!("USB serial missing or placeholder, reading serial from card");
+        serial_from_card(device_type, card_root?).as_deref().and_then(normalize_serial)
+    })
+}
+
+/// Uppercases the serial and drops anything that isn't ASCII alphanumeric.
+/// Returns `None` for placeholders.
+pub fn normalize_serial(raw: &str) -> Option<String> {
+    let serial: String = raw
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    let first = serial.chars().next()?;
+    if serial.chars().all(|c| c == first) || PLACEHOLDER_SERIALS.contains(&serial.as_str()) {
+        return None;
+    }
+
+    Some(serial)
+}
+
+fn serial_from_card(device_type: DeviceType, mount_point: &Path) -> Option<String> {
+    match device_type.vendor() {
+        VendorType::Insta => newest_file(mount_point, INSTA360_SERIAL_FILES).and_then(|p| read_insta360_serial(&p)),
+        // Thetas always report their real serial over USB
+        VendorType::Theta => None,
+    }
+}
+
+/// The normalized serial number of the camera that recorded `path`, for
+/// cameras that write it into their files
+pub fn serial_from_file(device_type: DeviceType, path: &Path) -> Option<String> {
+    let extension = path.extension()?;
+    match device_type.vendor() {
+        VendorType::Insta if INSTA360_SERIAL_FILES.iter().any(|x| extension.eq_ignore_ascii_case(x)) => {
+            read_insta360_serial(path).as_deref().and_then(normalize_serial)
+        }
+        VendorType::Insta | VendorType::Theta => None,
+    }
+}
+
+fn newest_file(volume: &Path, extensions: &[&str]) -> Option<PathBuf> {
+    WalkDir::new(volume)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| {
+            e.path()
+                .extension()
+                .is_some_and(|ext| extensions.iter().any(|x| ext.eq_ignore_ascii_case(x)))
+        })
+        .max_by_key(|e| e.metadata().ok().and_then(|m| m.modified().ok()))
+        .map(|e| e.into_path())
+}
+
+/// Reads the serial number out of the info record in an Insta360 file trailer.
+/// Records are laid out back to front: `[data][id: u16 LE][len: u32 LE]`, ending
+/// in a fixed 78-byte block whose bytes 38..42 hold the total trailer length.
+pub fn read_insta360_serial(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+    if file_len < INSV_TRAILER_LEN {
+        return None;
+    }
+
+    let mut tail = [0u8; INSV_TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(file_len - INSV_TRAILER_LEN)).ok()?;
+    file.read_exact(&mut tail).ok()?;
+    if !tail.ends_with(INSV_TRAILER_MAGIC) {
+        return None;
+    }
+
+    let trailer_len = u32::from_le_bytes(tail[38..42].try_into().ok()?) as u64;
+    let trailer_start = file_len.checked_sub(trailer_len)?;
+    let mut pos = file_len - INSV_TRAILER_LEN;
+
+    while pos >= trailer_start + 6 {
+        let mut header = [0u8; 6];
+        file.seek(SeekFrom::Start(pos - 6)).ok()?;
+        file.read_exact(&mut header).ok()?;
+
+        let id = u16::from_le_bytes([header[0], header[1]]);
+        let len = u32::from_le_bytes(header[2..6].try_into().ok()?) as u64;
+        let data_start = (pos - 6).checked_sub(len)?;
+
+        if id == INSV_INFO_RECORD {
+            if len > INSV_INFO_MAX_LEN {
+                return None;
+            }
+            let mut data = vec![0u8; len as usize];
+            file.seek(SeekFrom::Start(data_start)).ok()?;
+            file.read_exact(&mut data).ok()?;
+            return find_string_field(&data, INSV_INFO_SERIAL_TAG).filter(|s| s.len() <= MAX_SERIAL_LEN);
+        }
+
+        pos = data_start;
+    }
+
+    None
+}
+
+// The info record is a flat protobuf message of length-delimited string fields
+fn find_string_field(data: &[u8], tag: u8) -> Option<String> {
+    let mut pos = 0;
+
+    while pos + 1 < data.len() {
+        let field = data[pos];
+        let len = data[pos + 1] as usize;
+        let value = data.get(pos + 2..pos + 2 + len)?;
+
+        if field == tag {
+            return String::from_utf8(value.to_vec()).ok();
+        }
+
+        pos += 2 + len;
+    }
+
+    None
+}
+
+/// Fake recordings, for tests elsewhere in the crate
+#[cfg(test)]
+pub mod test_files {
+    use super::*;
+    use std::fs;
+
+    /// An Insta360 file whose trailer holds `serial`
+    pub fn write_insv(path: &Path, serial: &str) {
+        let mut info = vec![INSV_INFO_SERIAL_TAG, serial.len() as u8];
+        info.extend_from_slice(serial.as_bytes());
+        info.extend_from_slice(&[0x12, 6]);
+        info.extend_from_slice(b"OneX2 ");
+
+        let mut trailer = info.clone();
+        trailer.extend_from_slice(&INSV_INFO_RECORD.to_le_bytes());
+        trailer.extend_from_slice(&(info.len() as u32).to_le_bytes());
+
+        let mut tail = vec![0u8; INSV_TRAILER_LEN as usize];
+        let total = (trailer.len() as u64 + INSV_TRAILER_LEN) as u32;
+        tail[38..42].copy_from_slice(&total.to_le_bytes());
+        tail[46..].copy_from_slice(INSV_TRAILER_MAGIC);
+
+        let mut contents = vec![0xAB; 1024];
+        contents.extend(trailer);
+        contents.extend(tail);
+        fs::write(path, contents).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_files::write_insv;
+    use super::*;
+    use crate::device_type::test_devices;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn temp_volume() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("DCIM/Camera01")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn insta360_one_x2_uses_usb_serial() {
+        let serial = resolve_serial(test_devices::insta360_one_x2(), Some(" ixse42-abcdef "), None);
+        assert_eq!(serial.as_deref(), Some("IXSE42ABCDEF"));
+    }
+
+    #[test]
+    fn insta360_one_x2_falls_back_to_card_serial() {
+        let volume = temp_volume();
+        write_insv(&volume.path().join("DCIM/Camera01/VID_20240101_120000_00_001.insv"), "IXSE42CAFEBEEF");
+
+        let serial = resolve_serial(test_devices::insta360_one_x2(), Some("0123456789ABCDEF"), Some(volume.path()));
+        assert_eq!(serial.as_deref(), Some("IXSE42CAFEBEEF"));
+    }
+
+    #[test]
+    fn insta360_one_x2_without_any_serial() {
+        let volume = temp_volume();
+        assert_eq!(resolve_serial(test_devices::insta360_one_x2(), None, Some(volume.path())), None);
+    }
+
+    #[test]
+    fn insta360_serial_rejects_oversized_records() {
+        let volume = temp_volume();
+        let path = volume.path().join("DCIM/Camera01/VID_20240101_120000_00_001.insv");
+        write_insv(&path, "IXSE42CAFEBEEF");
+
+        // Stretch the info record over 6000 bytes of zeros in front of it, which
+        // would still parse as empty fields if the length weren't capped
+        let mut contents = vec![0u8; 8192];
+        contents.extend(fs::read(&path).unwrap().into_iter().map(|b| if b == 0xAB { 0 } else { b }));
+        let header = contents.len() - INSV_TRAILER_LEN as usize - 4;
+        let len = u32::from_le_bytes(contents[header..header + 4].try_into().unwrap()) + 6000;
+        contents[header..header + 4].copy_from_slice(&len.to_le_bytes());
+        fs::write(&path, contents).unwrap();
+
+        assert_eq!(read_insta360_serial(&path), None);
+    }
+
+    #[test]
+    fn theta_z1_uses_usb_serial() {
+        let serial = resolve_serial(test_devices::theta_z1(), Some("10010123"), None);
+        assert_eq!(serial.as_deref(), Some("10010123"));
+    }
+
+    #[test]
+    fn theta_z1_rejects_placeholder() {
+        assert_eq!(resolve_serial(test_devices::theta_z1(), Some("00000000"), None), None);
+    }
+
+    #[test]
+    fn placeholder_serials() {
+        assert_eq!(normalize_serial(""), None);
+        assert_eq!(normalize_serial("unknown"), None);
+        assert_eq!(normalize_serial("0123456789abcdef"), None);
+        assert_eq!(normalize_serial("ffffffff"), None);
+        assert_eq!(normalize_serial("insx-ecafebeef").as_deref(), Some("INSXECAFEBEEF"));
+    }
+}
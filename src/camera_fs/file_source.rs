@@ -0,0 +1,329 @@
+use crate::camera_fs::camera::{Camera, FileStrategy};
+use futures::executor::block_on;
+use mtp_rs::mtp::{MtpDevice, Storage};
+use mtp_rs::ptp::ObjectInfo;
+use mtp_rs::{DateTime, ObjectHandle};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
+
+// GetPartialObject only takes a 32-bit offset, and larger objects report this as their size
+const MTP_MAX_FILE_SIZE: u64 = u32::MAX as u64;
+// Guards the parent walk against a device reporting a cycle
+const MTP_MAX_DEPTH: usize = 32;
+
+/// A file on a camera, addressed by its `/`-separated path relative to the
+/// root of the source, e.g. `DCIM/Camera01/VID_20240101_120000_00_001.insv`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub path: String,
+    pub size: u64,
+    // Seconds since the Unix epoch, when the source reports it
+    pub modified: Option<u64>,
+}
+
+impl SourceFile {
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// Where a camera's files are read from. Calls block, so the async upload
+/// code runs them with `spawn_blocking`.
+pub trait FileSource: Send + Sync {
+    fn list_files(&self) -> io::Result<Vec<SourceFile>>;
+
+    /// Reads up to `len` bytes starting at `offset`; fewer only at end of file
+    fn read_range(&self, file: &SourceFile, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+}
+
+/// Connects to wherever the camera keeps its files: one source per mounted
+/// volume or folder, or a single MTP session covering every storage.
+pub fn open_file_sources(camera: &Camera) -> io::Result<Vec<Arc<dyn FileSource>>> {
+    match camera.fs_strategy {
+        FileStrategy::Mount | FileStrategy::Folder => Ok(camera
+            .volumes
+            .iter()
+            .filter_map(|v| v.mount_point.clone())
+            .map(|root| Arc::new(MountedSource::new(root)) as Arc<dyn FileSource>)
+            .collect()),
+        FileStrategy::Mtp { location_id } => Ok(vec![Arc::new(MtpSource::open(location_id)?)]),
+    }
+}
+
+/**
+ * A mass storage volume mounted into the local filesystem
+*/
+pub struct MountedSource {
+    root: PathBuf,
+}
+
+impl MountedSource {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn full_path(&self, file: &SourceFile) -> PathBuf {
+        self.root.join(Path::new(&file.path))
+    }
+}
+
+impl FileSource for MountedSource {
+    fn list_files(&self) -> io::Result<Vec<SourceFile>> {
+        let mut files = Vec::new();
+
+        for entry in WalkDir::new(&self.root).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Error reading directory entry: {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let Ok(relative) = entry.path().strip_prefix(&self.root) else {
+                continue;
+            };
+            let path = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let metadata = entry.metadata()?;
+            files.push(SourceFile {
+                path,
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn read_range(&self, file: &SourceFile, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file_handle = File::open(self.full_path(file))?;
+        let mut buffer = Vec::with_capacity(len as usize);
+
+        file_handle.seek(SeekFrom::Start(offset))?;
+        file_handle.take(len).read_to_end(&mut buffer)?;
+
+        Ok(buffer)
+    }
+}
+
+/**
+ * A camera that only speaks MTP, like the Theta Z1. mtp-rs is runtime
+ * agnostic, so its futures are simply driven to completion on the calling thread.
+*/
+pub struct MtpSource {
+    // Keeps the session open for the storages
+    _device: MtpDevice,
+    storages: Vec<Storage>,
+    // Path -> (storage index, object) from the last listing
+    objects: Mutex<HashMap<String, (usize, ObjectHandle)>>,
+}
+
+impl MtpSource {
+    pub fn open(location_id: u64) -> io::Result<Self> {
+        block_on(async {
+            let device = MtpDevice::open_by_location(location_id).await.map_err(io::Error::other)?;
+            let storages = device.storages().await.map_err(io::Error::other)?;
+
+            Ok(Self {
+                _device: device,
+                storages,
+                objects: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+}
+
+impl FileSource for MtpSource {
+    fn list_files(&self) -> io::Result<Vec<SourceFile>> {
+        let mut files = Vec::new();
+        let mut objects = HashMap::new();
+
+        for (index, storage) in self.storages.iter().enumerate() {
+            let listing = block_on(storage.list_objects_recursive(None)).map_err(io::Error::other)?;
+            let by_handle: HashMap<ObjectHandle, &ObjectInfo> = listing.iter().map(|o| (o.handle, o)).collect();
+
+            for object in listing.iter().filter(|o| o.is_file()) {
+                if object.size >= MTP_MAX_FILE_SIZE {
+                    eprintln!("Skipping {}: files over 4 GB can't be read over MTP", object.filename);
+                    continue;
+                }
+
+                let mut path = object_path(object, &by_handle);
+                // Keep paths unique when a camera has more than one storage
+                if self.storages.len() > 1 {
+                    path = format!("{}/{}", storage.info().description, path);
+                }
+
+                objects.insert(path.clone(), (index, object.handle));
+                files.push(SourceFile {
+                    path,
+                    size: object.size,
+                    modified: object.modified.as_ref().map(unix_seconds),
+                });
+            }
+        }
+
+        *self.objects.lock().map_err(|_| io::Error::other("MTP object cache poisoned"))? = objects;
+
+        Ok(files)
+    }
+
+    fn read_range(&self, file: &SourceFile, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let (index, handle) = self
+            .objects
+            .lock()
+            .map_err(|_| io::Error::other("MTP object cache poisoned"))?
+            .get(&file.path)
+            .copied()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found on camera", file.path)))?;
+
+        let len = len.min(file.size.saturating_sub(offset)) as u32;
+        block_on(self.storages[index].download_partial(handle, offset, len)).map_err(io::Error::other)
+    }
+}
+
+// Joins the names of the object and its parent folders
+fn object_path(object: &ObjectInfo, by_handle: &HashMap<ObjectHandle, &ObjectInfo>) -> String {
+    let mut names = vec![object.filename.as_str()];
+    let mut parent = object.parent;
+
+    while let Some(folder) = by_handle.get(&parent) {
+        if names.len() >= MTP_MAX_DEPTH {
+            break;
+        }
+        names.push(folder.filename.as_str());
+        parent = folder.parent;
+    }
+
+    names.reverse();
+    names.join("/")
+}
+
+// Cameras report local time without a zone, which is taken as UTC here: the
+// value only has to stay the same for a file between runs.
+fn unix_seconds(time: &DateTime) -> u64 {
+    // Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's days_from_civil)
+    let year = time.year as i64 - if time.month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = time.month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + time.day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let seconds = days * 86400 + time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    seconds.max(0) as u64
+}
+
+/**
+ * Files held in memory, standing in for a camera in tests
+*/
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl MemorySource {
+    pub fn with_file(mut self, path: &str, content: impl Into<Vec<u8>>) -> Self {
+        self.files.insert(path.to_string(), content.into());
+        self
+    }
+}
+
+#[cfg(test)]
+impl FileSource for MemorySource {
+    fn list_files(&self) -> io::Result<Vec<SourceFile>> {
+        let mut files: Vec<SourceFile> = self
+            .files
+            .iter()
+            .map(|(path, content)| SourceFile {
+                path: path.clone(),
+                size: content.len() as u64,
+                modified: None,
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(files)
+    }
+
+    fn read_range(&self, file: &SourceFile, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let content = self
+            .files
+            .get(&file.path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, file.path.clone()))?;
+
+        let start = (offset as usize).min(content.len());
+        let end = (start + len as usize).min(content.len());
+
+        Ok(content[start..end].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_file_name() {
+        let file = SourceFile {
+            path: "DCIM/Camera01/VID_20240101_120000_00_001.INSV".to_string(),
+            size: 0,
+            modified: None,
+        };
+
+        assert_eq!(file.name(), "VID_20240101_120000_00_001.INSV");
+    }
+
+    #[test]
+    fn mtp_timestamps_to_unix_seconds() {
+        assert_eq!(unix_seconds(&DateTime::new(1970, 1, 1, 0, 0, 0).unwrap()), 0);
+        assert_eq!(unix_seconds(&DateTime::new(2024, 2, 29, 12, 30, 15).unwrap()), 1709209815);
+    }
+
+    #[test]
+    fn memory_source_reads_ranges() {
+        let source = MemorySource::default().with_file("DCIM/a.insv", b"0123456789".to_vec());
+        let files = source.list_files().unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(source.read_range(&files[0], 2, 3).unwrap(), b"234");
+        // Short read at the end of the file
+        assert_eq!(source.read_range(&files[0], 8, 5).unwrap(), b"89");
+    }
+
+    #[test]
+    fn mounted_source_lists_relative_paths() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("DCIM/Camera01")).unwrap();
+        std::fs::write(root.path().join("DCIM/Camera01/VID_1.insv"), b"hello world").unwrap();
+
+        let source = MountedSource::new(root.path().to_path_buf());
+        let files = source.list_files().unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "DCIM/Camera01/VID_1.insv");
+        assert_eq!(files[0].size, 11);
+        assert!(files[0].modified.is_some());
+        assert_eq!(source.read_range(&files[0], 6, 5).unwrap(), b"world");
+    }
+}
@@ -0,0 +1,342 @@
+use crate::camera_fs::camera::Camera;
+use crate::camera_fs::camera_finder::scan_for_camera_fs;
+use crate::device_type::DeviceType;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+// How often cameras are scanned for where kernel hotplug events aren't available
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// What `pause_polling` holds off: the sources from `open_hotplug_source`
+static POLLING_PAUSES: LazyLock<PollingPauses> = LazyLock::new(PollingPauses::default);
+
+/// A supported camera being plugged in or unplugged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugEvent {
+    Added(DeviceType),
+    Removed(DeviceType),
+}
+
+/**
+ * Where USB hotplug events come from: the kernel on Linux, periodic camera
+ * scans elsewhere, or scripted events in tests
+*/
+pub trait HotplugSource: Send {
+    /// Waits up to `timeout` for the next event, `None` if nothing happened
+    fn next_event(&mut self, timeout: Duration) -> io::Result<Option<HotplugEvent>>;
+}
+
+/// Kernel hotplug events when they can be subscribed to, camera scans otherwise
+pub fn open_hotplug_source() -> Box<dyn HotplugSource> {
+    #[cfg(target_os = "linux")]
+    {
+        match netlink::NetlinkSource::open() {
+            Ok(source) => return Box::new(source),
+            Err(e) => eprintln!("USB hotplug events unavailable, polling for cameras instead: {}", e),
+        }
+    }
+
+    Box::new(PollingSource::new(POLL_INTERVAL, POLLING_PAUSES.clone()))
+}
+
+/// Pauses the camera polling of `open_hotplug_source` for as long as the
+/// returned guard lives
+pub fn pause_polling() -> PollingPause {
+    POLLING_PAUSES.pause()
+}
+
+/**
+ * Whether the `PollingSource`s sharing this are held off. Scanning opens an
+ * MTP session on each camera, which would get in the way of an upload reading
+ * from one.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PollingPauses {
+    // Live `PollingPause`s
+    count: Arc<AtomicUsize>,
+}
+
+impl PollingPauses {
+    pub fn pause(&self) -> PollingPause {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        PollingPause { count: self.count.clone() }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.count.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Holds off polling until dropped
+pub struct PollingPause {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for PollingPause {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Scans for cameras every `interval` and reports the ones that came or went.
+/// Cameras connected at the first scan are taken as already there. No scans
+/// run while `pauses` says so; changes in the meantime show up in the first
+/// scan after.
+pub struct PollingSource {
+    interval: Duration,
+    scan_cameras: Box<dyn FnMut() -> Vec<Camera> + Send>,
+    pauses: PollingPauses,
+    connected: Option<HashMap<String, DeviceType>>,
+    pending: VecDeque<HotplugEvent>,
+    next_scan: Instant,
+}
+
+impl PollingSource {
+    pub fn new(interval: Duration, pauses: PollingPauses) -> Self {
+        Self::with_scan(interval, pauses, scan_for_camera_fs)
+    }
+
+    fn with_scan(
+        interval: Duration,
+        pauses: PollingPauses,
+        scan_cameras: impl FnMut() -> Vec<Camera> + Send + 'static,
+    ) -> Self {
+        Self {
+            interval,
+            scan_cameras: Box::new(scan_cameras),
+            pauses,
+            connected: None,
+            pending: VecDeque::new(),
+            next_scan: Instant::now(),
+        }
+    }
+
+    fn scan(&mut self) {
+        let cameras: HashMap<String, DeviceType> = (self.scan_cameras)()
+            .into_iter()
+            .map(|c| (c.device_id, c.device_type))
+            .collect();
+
+        if let Some(previous) = &self.connected {
+            for (device_id, device_type) in &cameras {
+                if !previous.contains_key(device_id) {
+                    self.pending.push_back(HotplugEvent::Added(*device_type));
+                }
+            }
+            for (device_id, device_type) in previous {
+                if !cameras.contains_key(device_id) {
+                    self.pending.push_back(HotplugEvent::Removed(*device_type));
+                }
+            }
+        }
+        self.connected = Some(cameras);
+    }
+}
+
+impl HotplugSource for PollingSource {
+    fn next_event(&mut self, timeout: Duration) -> io::Result<Option<HotplugEvent>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let now = Instant::now();
+            let paused = self.pauses.is_paused();
+            if now >= self.next_scan && !paused {
+                self.scan();
+                self.next_scan = now + self.interval;
+            } else if now >= deadline {
+                return Ok(None);
+            } else {
+                // A scan that came due while paused runs once the pause ends
+                let wake = if paused { deadline } else { self.next_scan.min(deadline) };
+                std::thread::sleep(wake - now);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use super::{HotplugEvent, HotplugSource};
+    use crate::device_type::DeviceType;
+    use std::collections::HashMap;
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::{Duration, Instant};
+
+    // Multicast group the kernel sends uevents to (udev re-broadcasts on group 2)
+    const KERNEL_UEVENT_GROUP: u32 = 1;
+    // uevents are capped at 2048 bytes of environment plus the header
+    const UEVENT_BUFFER_SIZE: usize = 8192;
+
+    /// Kernel uevents from a NETLINK_KOBJECT_UEVENT socket, the stream udev reads
+    pub struct NetlinkSource {
+        fd: OwnedFd,
+    }
+
+    impl NetlinkSource {
+        pub fn open() -> io::Result<Self> {
+            // SAFETY: socket() returns a new descriptor or -1, and bind() only
+            // reads the address it is given
+            unsafe {
+                let fd = libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_KOBJECT_UEVENT,
+                );
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let fd = OwnedFd::from_raw_fd(fd);
+
+                let mut addr: libc::sockaddr_nl = mem::zeroed();
+                addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+                addr.nl_groups = KERNEL_UEVENT_GROUP;
+                let result = libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                );
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(Self { fd })
+            }
+        }
+    }
+
+    impl HotplugSource for NetlinkSource {
+        fn next_event(&mut self, timeout: Duration) -> io::Result<Option<HotplugEvent>> {
+            let deadline = Instant::now() + timeout;
+            let mut buffer = [0u8; UEVENT_BUFFER_SIZE];
+
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let mut pollfd = libc::pollfd {
+                    fd: self.fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: one valid pollfd
+                let ready = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis().min(i32::MAX as u128) as i32) };
+                if ready < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                if ready == 0 {
+                    return Ok(None);
+                }
+
+                // SAFETY: recv writes at most buffer.len() bytes into buffer
+                let len = unsafe { libc::recv(self.fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+                if len < 0 {
+                    let e = io::Error::last_os_error();
+                    match e.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // The socket buffer overflowed and some events were
+                        // lost, which only matters if they were ours
+                        Some(libc::ENOBUFS) => continue,
+                        _ => return Err(e),
+                    }
+                }
+
+                // Most uevents are about other devices, or interfaces of ours
+                if let Some(event) = parse_uevent(&buffer[..len as usize]) {
+                    return Ok(Some(event));
+                }
+            }
+        }
+    }
+
+    /// Reads a kernel uevent, e.g. `add@/devices/...\0ACTION=add\0SUBSYSTEM=usb\0
+    /// DEVTYPE=usb_device\0PRODUCT=70a/4026/100\0...`, keeping only whole USB
+    /// devices the registry knows
+    pub(super) fn parse_uevent(message: &[u8]) -> Option<HotplugEvent> {
+        let fields: HashMap<&str, &str> = message
+            .split(|b| *b == 0)
+            .filter_map(|field| std::str::from_utf8(field).ok()?.split_once('='))
+            .collect();
+
+        if fields.get("SUBSYSTEM") != Some(&"usb") || fields.get("DEVTYPE") != Some(&"usb_device") {
+            return None;
+        }
+
+        // "vendor/product/bcdDevice" in hex without leading zeros
+        let mut product = fields.get("PRODUCT")?.split('/');
+        let vendor_id = u16::from_str_radix(product.next()?, 16).ok()?;
+        let product_id = u16::from_str_radix(product.next()?, 16).ok()?;
+        let device_type = DeviceType::from_product_id(vendor_id, product_id)?;
+
+        match *fields.get("ACTION")? {
+            "add" => Some(HotplugEvent::Added(device_type)),
+            "remove" => Some(HotplugEvent::Removed(device_type)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::netlink::parse_uevent;
+    use super::*;
+    use crate::device_type::test_devices;
+
+    fn uevent(fields: &[&str]) -> Vec<u8> {
+        let mut message = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2".to_vec();
+        for field in fields {
+            message.push(0);
+            message.extend_from_slice(field.as_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn parses_camera_uevents() {
+        let added = uevent(&["ACTION=add", "SUBSYSTEM=usb", "DEVTYPE=usb_device", "PRODUCT=70a/4026/100"]);
+        let removed = uevent(&["ACTION=remove", "SUBSYSTEM=usb", "DEVTYPE=usb_device", "PRODUCT=5ca/36d/100"]);
+
+        assert_eq!(parse_uevent(&added), Some(HotplugEvent::Added(test_devices::insta360_one_x2())));
+        assert_eq!(parse_uevent(&removed), Some(HotplugEvent::Removed(test_devices::theta_z1())));
+    }
+
+    #[test]
+    fn ignores_other_uevents() {
+        let interface = uevent(&["ACTION=add", "SUBSYSTEM=usb", "DEVTYPE=usb_interface", "PRODUCT=70a/4026/100"]);
+        let keyboard = uevent(&["ACTION=add", "SUBSYSTEM=usb", "DEVTYPE=usb_device", "PRODUCT=46d/c31c/6400"]);
+        let bind = uevent(&["ACTION=bind", "SUBSYSTEM=usb", "DEVTYPE=usb_device", "PRODUCT=70a/4026/100"]);
+
+        assert_eq!(parse_uevent(&interface), None);
+        assert_eq!(parse_uevent(&keyboard), None);
+        assert_eq!(parse_uevent(&bind), None);
+        assert_eq!(parse_uevent(b"libudev\0garbage"), None);
+    }
+
+    #[test]
+    fn polling_pauses_while_uploading() {
+        let (pauses, scans) = (PollingPauses::default(), Arc::new(AtomicUsize::new(0)));
+        let counted = scans.clone();
+        let mut source = PollingSource::with_scan(Duration::from_secs(3600), pauses.clone(), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Vec::new()
+        });
+
+        let pause = pauses.pause();
+        source.next_event(Duration::ZERO).unwrap();
+        assert_eq!(scans.load(Ordering::SeqCst), 0);
+
+        drop(pause);
+        source.next_event(Duration::ZERO).unwrap();
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,3 +1,7 @@
 pub mod camera_finder;
+mod device_id;
 mod sys_profiler_usb;
-mod camera;
\ No newline at end of file
+mod sysfs_usb;
+pub mod camera;
+pub mod file_source;
+pub mod hotplug;
\ No newline at end of file
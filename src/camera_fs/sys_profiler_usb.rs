@@ -116,6 +116,9 @@ pub struct UsbVolume {
     #[serde(rename = "size_in_bytes", default)]
     pub size_in_bytes: Option<u64>,
 
+    #[serde(rename = "free_space_in_bytes", default)]
+    pub free_space_in_bytes: Option<u64>,
+
     #[serde(rename = "volume_uuid", default)]
     pub volume_uuid: Option<String>,
 }
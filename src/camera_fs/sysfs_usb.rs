@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub const SYSFS_USB_DEVICES: &str = "/sys/bus/usb/devices";
+pub const PROC_MOUNTINFO: &str = "/proc/self/mountinfo";
+
+// usb device -> interface -> scsi host -> target -> lun -> block -> disk -> partition
+const MAX_BLOCK_DEPTH: usize = 10;
+
+/**
+ * Data model for the USB devices exposed by the Linux sysfs tree
+*/
+#[derive(Debug)]
+pub struct SysfsUsbDevice {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub product: Option<String>,
+    pub serial_num: Option<String>,
+    // "major:minor" of every disk and partition, as found in mountinfo
+    pub block_devices: Vec<String>,
+}
+
+/**
+ * A single line of /proc/self/mountinfo
+*/
+#[derive(Debug)]
+pub struct MountInfoEntry {
+    pub dev: String,
+    pub mount_point: PathBuf,
+}
+
+fn read_attr(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn read_hex_attr(dir: &Path, name: &str) -> Option<u16> {
+    read_attr(dir, name).and_then(|s| u16::from_str_radix(&s, 16).ok())
+}
+
+/// Lists every USB device under `sysfs_root` (normally /sys/bus/usb/devices).
+/// Interface entries such as `1-1:1.0` carry no idVendor and are skipped.
+pub fn read_usb_devices(sysfs_root: &Path) -> Vec<SysfsUsbDevice> {
+    let Ok(entries) = fs::read_dir(sysfs_root) else {
+        eprintln!("Could not read {}", sysfs_root.display());
+        return Vec::new();
+    };
+
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter(|p| p.join("idVendor").is_file())
+        .map(|path| SysfsUsbDevice {
+            vendor_id: read_hex_attr(&path, "idVendor"),
+            product_id: read_hex_attr(&path, "idProduct"),
+            product: read_attr(&path, "product"),
+            serial_num: read_attr(&path, "serial"),
+            block_devices: read_block_devices(&path),
+        })
+        .collect()
+}
+
+/// Finds the disks and partitions that hang off a USB device. Entries in
+/// /sys/bus/usb/devices are symlinks, so the device is resolved first and its
+/// subtree walked without following the `subsystem`/`driver` back-links.
+fn read_block_devices(device: &Path) -> Vec<String> {
+    let root = fs::canonicalize(device).unwrap_or_else(|_| device.to_path_buf());
+
+    WalkDir::new(root)
+        .max_depth(MAX_BLOCK_DEPTH)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .filter(|e| e.path().components().any(|c| c.as_os_str() == "block"))
+        .filter_map(|e| read_attr(e.path(), "dev"))
+        .collect()
+}
+
+/// Parses mountinfo (see proc(5)). Unreadable files or malformed lines are
+/// treated as "nothing mounted".
+pub fn read_mountinfo(path: &Path) -> Vec<MountInfoEntry> {
+    match fs::read_to_string(path) {
+        Ok(content) => content.lines().filter_map(parse_mountinfo_line).collect(),
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+fn parse_mountinfo_line(line: &str) -> Option<MountInfoEntry> {
+    let (mount, _fs) = line.split_once(" - ")?;
+    let fields: Vec<&str> = mount.split_whitespace().collect();
+    let dev = fields.get(2)?.to_string();
+    let mount_point = PathBuf::from(unescape_mount_path(fields.get(4)?));
+
+    Some(MountInfoEntry { dev, mount_point })
+}
+
+// The kernel escapes space, tab, newline and backslash as 3-digit octal (e.g. "\040")
+fn unescape_mount_path(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(octal, 8) {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mountinfo_lines() {
+        let entry = parse_mountinfo_line(
+            "62 28 8:17 / /media/user/THETA\\040Z1 rw,nosuid,nodev relatime shared:33 - exfat /dev/sdb1 rw,fmask=0022",
+        )
+        .unwrap();
+
+        assert_eq!(entry.dev, "8:17");
+        assert_eq!(entry.mount_point, PathBuf::from("/media/user/THETA Z1"));
+        assert!(parse_mountinfo_line("62 28 8:17 / /media/user/THETA").is_none());
+    }
+
+    #[test]
+    fn unescapes_octal_mount_paths() {
+        assert_eq!(unescape_mount_path("/a\\040b\\011c\\134d"), "/a b\tc\\d");
+        // Not an escape, kept as is
+        assert_eq!(unescape_mount_path("/a\\09x"), "/a\\09x");
+        assert_eq!(unescape_mount_path("/a\\04"), "/a\\04");
+    }
+}
@@ -0,0 +1,329 @@
+use crate::openspace::retry::RetryPolicy;
+use crate::openspace::upload_all_files::UploadConcurrency;
+use crate::storage::get_storage_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+const CONFIG_FILE: &str = "config.toml";
+
+// Built-in settings for a backend running on this machine
+const DEFAULT_API_BASE_URL: &str = "http://localhost:8080/api";
+const DEFAULT_AUTH0_CLIENT_ID: &str = "B85VbSiRcD92gcDOhgfQG6CPueV2HgwH";
+const DEFAULT_AUTH0_DOMAIN: &str = "login.openspace.ai";
+
+// Environment variables override whatever config.toml says
+const ENV_ENVIRONMENT: &str = "OPENSPACE_ENV";
+const ENV_API_BASE_URL: &str = "OPENSPACE_API_BASE_URL";
+const ENV_AUTH0_DOMAIN: &str = "OPENSPACE_AUTH0_DOMAIN";
+const ENV_AUTH0_CLIENT_ID: &str = "OPENSPACE_AUTH0_CLIENT_ID";
+const ENV_PARALLEL_FILES: &str = "OPENSPACE_PARALLEL_FILES";
+const ENV_PARALLEL_PARTS: &str = "OPENSPACE_PARALLEL_PARTS";
+const ENV_MAX_RETRIES: &str = "OPENSPACE_MAX_RETRIES";
+
+static SETTINGS: LazyLock<Result<Settings, String>> = LazyLock::new(|| load_settings().map_err(|e| e.to_string()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Local,
+    Staging,
+    Prod,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "local" => Ok(Environment::Local),
+            "staging" => Ok(Environment::Staging),
+            "prod" | "production" => Ok(Environment::Prod),
+            other => Err(format!("Unknown environment '{}', expected local, staging or prod", other)),
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Environment::Local => write!(f, "local"),
+            Environment::Staging => write!(f, "staging"),
+            Environment::Prod => write!(f, "prod"),
+        }
+    }
+}
+
+/// Backend and login endpoints of one environment. Keys left out of an
+/// `[environments.<name>]` table fall back to the local defaults, misspelled
+/// ones are an error.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvironmentConfig {
+    pub api_base_url: String,
+    pub auth0_domain: String,
+    pub auth0_client_id: String,
+    pub auth0_audience: Option<String>,
+    // Loopback port for the login redirect, 0 picks any free port
+    pub auth0_redirect_port: u16,
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        Self {
+            api_base_url: DEFAULT_API_BASE_URL.to_string(),
+            auth0_domain: DEFAULT_AUTH0_DOMAIN.to_string(),
+            auth0_client_id: DEFAULT_AUTH0_CLIENT_ID.to_string(),
+            auth0_audience: None,
+            auth0_redirect_port: 0,
+        }
+    }
+}
+
+/// Upload tuning; anything unset uses the upload engine's own defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    pub parallel_files: Option<usize>,
+    pub parallel_parts: Option<usize>,
+    pub max_retries: Option<u32>,
+    // Send a SHA-256 of each file along with its MD5
+    pub sha256: bool,
+    // Device IDs of cameras set to record one lens only, whose lone front lens
+    // videos are uploaded instead of waiting for a back lens half
+    pub single_lens_devices: Vec<String>,
+}
+
+impl UploadConfig {
+    pub fn concurrency(&self) -> UploadConcurrency {
+        let defaults = UploadConcurrency::default();
+        UploadConcurrency {
+            files: self.parallel_files.unwrap_or(defaults.files),
+            parts_per_file: self.parallel_parts.unwrap_or(defaults.parts_per_file),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            max_retries: self.max_retries.unwrap_or(defaults.max_retries),
+            ..defaults
+        }
+    }
+}
+
+/// Syncing cameras as soon as they are plugged in
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoSyncConfig {
+    // Start watching for cameras when the desktop app opens
+    pub enabled: bool,
+    // Keep the desktop app running in the system tray when its window is closed
+    pub tray: bool,
+}
+
+/**
+ * Data model for ~/.openspace_sync/config.toml, e.g.
+ *
+ * environment = "staging"
+ *
+ * [environments.staging]
+ * api_base_url = "https://staging.example.com/api"
+ *
+ * [upload]
+ * parallel_files = 3
+ * single_lens_devices = ["Insta360 OneX2:sn:IXSE1234567"]
+ *
+ * [auto_sync]
+ * enabled = true
+*/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConfigFile {
+    pub environment: Environment,
+    pub environments: HashMap<Environment, EnvironmentConfig>,
+    pub upload: UploadConfig,
+    pub auto_sync: AutoSyncConfig,
+}
+
+/// The effective configuration: config.toml with env var overrides applied
+/// and the active environment resolved.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub environment: Environment,
+    pub api: EnvironmentConfig,
+    pub upload: UploadConfig,
+    pub auto_sync: AutoSyncConfig,
+}
+
+/// The effective configuration. The binaries call [`check_settings`] first, so
+/// a broken config stops them at startup instead of here.
+pub fn settings() -> &'static Settings {
+    match &*SETTINGS {
+        Ok(settings) => settings,
+        Err(e) => panic!("Invalid configuration: {}", e),
+    }
+}
+
+/// Loads the configuration once. An unreadable config.toml, or an environment
+/// it doesn't define, is an error rather than a silent fallback to localhost.
+pub fn check_settings() -> Result<&'static Settings, String> {
+    SETTINGS.as_ref().map_err(|e| format!("Invalid configuration: {}", e))
+}
+
+pub fn load_config_file() -> Result<ConfigFile, Box<dyn std::error::Error>> {
+    let config_path = get_storage_dir()?.join(CONFIG_FILE);
+
+    if !config_path.exists() {
+        return Ok(ConfigFile::default());
+    }
+
+    let content = fs::read_to_string(&config_path)?;
+    let config: ConfigFile = toml::from_str(&content).map_err(|e| format!("{}: {}", config_path.display(), e))?;
+
+    Ok(config)
+}
+
+pub fn load_settings() -> Result<Settings, Box<dyn std::error::Error>> {
+    resolve_settings(load_config_file()?, env_var)
+}
+
+/// Resolves the active environment of `config` and applies the overrides
+/// `env_var` returns
+fn resolve_settings(
+    mut config: ConfigFile,
+    env_var: impl Fn(&str) -> Option<String>,
+) -> Result<Settings, Box<dyn std::error::Error>> {
+    let environment = match env_var(ENV_ENVIRONMENT) {
+        Some(name) => name.parse::<Environment>()?,
+        None => config.environment,
+    };
+
+    let mut api = match (environment, config.environments.remove(&environment)) {
+        (_, Some(api)) => api,
+        (Environment::Local, None) => EnvironmentConfig::default(),
+        (env, None) => return Err(format!("No [environments.{}] section in {}", env, CONFIG_FILE).into()),
+    };
+
+    if let Some(url) = env_var(ENV_API_BASE_URL) {
+        api.api_base_url = url;
+    }
+    if let Some(domain) = env_var(ENV_AUTH0_DOMAIN) {
+        api.auth0_domain = domain;
+    }
+    if let Some(client_id) = env_var(ENV_AUTH0_CLIENT_ID) {
+        api.auth0_client_id = client_id;
+    }
+    api.api_base_url = api.api_base_url.trim_end_matches('/').to_string();
+    if !api.api_base_url.starts_with("http://") && !api.api_base_url.starts_with("https://") {
+        let url = &api.api_base_url;
+        return Err(format!("api_base_url of {} must be an http(s) URL, got '{}'", environment, url).into());
+    }
+    if api.auth0_domain.trim().is_empty() || api.auth0_client_id.trim().is_empty() {
+        return Err(format!("auth0_domain and auth0_client_id of {} can't be empty", environment).into());
+    }
+
+    let mut upload = config.upload;
+    if let Some(files) = env_var(ENV_PARALLEL_FILES) {
+        upload.parallel_files = Some(files.parse().map_err(|_| format!("{} must be a number", ENV_PARALLEL_FILES))?);
+    }
+    if let Some(parts) = env_var(ENV_PARALLEL_PARTS) {
+        upload.parallel_parts = Some(parts.parse().map_err(|_| format!("{} must be a number", ENV_PARALLEL_PARTS))?);
+    }
+    if let Some(retries) = env_var(ENV_MAX_RETRIES) {
+        upload.max_retries = Some(retries.parse().map_err(|_| format!("{} must be a number", ENV_MAX_RETRIES))?);
+    }
+
+    info!("Using {} environment ({})", environment, api.api_base_url);
+
+    Ok(Settings {
+        environment,
+        api,
+        upload,
+        auto_sync: config.auto_sync,
+    })
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+environment = "staging"
+
+[environments.staging]
+api_base_url = "https://staging.example.com/api/"
+auth0_domain = "staging-login.example.com"
+
+[upload]
+parallel_files = 3
+sha256 = true
+"#;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn reads_the_selected_environment() {
+        let config: ConfigFile = toml::from_str(CONFIG).unwrap();
+
+        let settings = resolve_settings(config, no_env).unwrap();
+
+        assert_eq!(settings.environment, Environment::Staging);
+        assert_eq!(settings.api.api_base_url, "https://staging.example.com/api");
+        assert_eq!(settings.api.auth0_domain, "staging-login.example.com");
+        // Left out, so the default
+        assert_eq!(settings.api.auth0_client_id, DEFAULT_AUTH0_CLIENT_ID);
+        assert_eq!(settings.upload.concurrency().files, 3);
+        assert!(settings.upload.sha256);
+    }
+
+    #[test]
+    fn env_vars_override_the_file() {
+        let config: ConfigFile = toml::from_str(CONFIG).unwrap();
+        let env = |name: &str| match name {
+            ENV_ENVIRONMENT => Some("local".to_string()),
+            ENV_API_BASE_URL => Some("http://127.0.0.1:9000/api".to_string()),
+            ENV_PARALLEL_PARTS => Some("8".to_string()),
+            _ => None,
+        };
+
+        let settings = resolve_settings(config, env).unwrap();
+
+        assert_eq!(settings.environment, Environment::Local);
+        assert_eq!(settings.api.api_base_url, "http://127.0.0.1:9000/api");
+        assert_eq!(settings.upload.concurrency().parts_per_file, 8);
+    }
+
+    #[test]
+    fn a_missing_or_invalid_environment_is_an_error() {
+        let env = |name: &str| (name == ENV_ENVIRONMENT).then(|| "prod".to_string());
+        let missing = resolve_settings(toml::from_str(CONFIG).unwrap(), env).unwrap_err();
+        assert!(missing.to_string().contains("[environments.prod]"), "{}", missing);
+
+        let unknown = |name: &str| (name == ENV_ENVIRONMENT).then(|| "qa".to_string());
+        assert!(resolve_settings(toml::from_str(CONFIG).unwrap(), unknown).is_err());
+
+        let bad_url: ConfigFile = toml::from_str("[environments.local]\napi_base_url = \"localhost:8080\"").unwrap();
+        assert!(resolve_settings(bad_url, no_env).is_err());
+
+        let bad_number = |name: &str| (name == ENV_PARALLEL_FILES).then(|| "many".to_string());
+        assert!(resolve_settings(ConfigFile::default(), bad_number).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_config_files() {
+        assert!(toml::from_str::<ConfigFile>("environment = \"qa\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[environments.prod]\napi_url = \"https://example.com\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[upload\nparallel_files = 3").is_err());
+    }
+}
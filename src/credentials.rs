@@ -0,0 +1,343 @@
+use crate::api::AuthTokens;
+use crate::config::{settings, Environment};
+use crate::storage::{commit_temp_file, get_environment_dir, temp_path_for, write_atomic};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Plaintext, readable only by the current user; used where there is no keychain
+const CREDENTIALS_FILE: &str = "credentials.json";
+// Encrypted with a key kept in the OS keychain
+const ENCRYPTED_CREDENTIALS_FILE: &str = "credentials.enc";
+// Starts files encrypted with a keychain key
+const ENCRYPTED_MAGIC: &[u8] = b"OSC1";
+const NONCE_LEN: usize = 12;
+// Keychain entries are "<environment>-credentials-key" under this service
+const KEYCHAIN_SERVICE: &str = "openspace-sync";
+
+/// Tokens of one OpenSpace account. Accounts are per environment, the same
+/// email on staging and prod is two accounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredAccount {
+    pub email: String,
+    pub environment: Environment,
+    pub tokens: AuthTokens,
+}
+
+/**
+ * Data model for ~/.openspace_sync/<environment>/credentials.enc, encrypted with
+ * a key from the OS keychain. Without a keychain (e.g. a headless Linux box
+ * with no Secret Service) it is kept as credentials.json instead, readable only
+ * by the current user.
+*/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CredentialStore {
+    pub accounts: Vec<StoredAccount>,
+    // Email of the account in use, per environment
+    pub active: HashMap<Environment, String>,
+}
+
+impl CredentialStore {
+    pub fn accounts(&self, environment: Environment) -> Vec<&StoredAccount> {
+        self.accounts.iter().filter(|a| a.environment == environment).collect()
+    }
+
+    pub fn active_account(&self, environment: Environment) -> Option<&StoredAccount> {
+        let email = self.active.get(&environment)?;
+        self.accounts
+            .iter()
+            .find(|a| a.environment == environment && &a.email == email)
+    }
+
+    /// Inserts or replaces the account and makes it the active one
+    pub fn upsert(&mut self, account: StoredAccount) {
+        self.accounts
+            .retain(|a| !(a.environment == account.environment && a.email == account.email));
+        self.active.insert(account.environment, account.email.clone());
+        self.accounts.push(account);
+    }
+
+    /// Removes the account; if it was active, another account of the same
+    /// environment (if any) takes over.
+    pub fn remove(&mut self, environment: Environment, email: &str) {
+        self.accounts.retain(|a| !(a.environment == environment && a.email == email));
+
+        if self.active.get(&environment).map(String::as_str) == Some(email) {
+            match self.accounts(environment).first().map(|a| a.email.clone()) {
+                Some(next) => self.active.insert(environment, next),
+                None => self.active.remove(&environment),
+            };
+        }
+    }
+
+    pub fn set_active(&mut self, environment: Environment, email: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.accounts(environment).iter().any(|a| a.email == email) {
+            return Err(format!("No saved account {} for {}", email, environment).into());
+        }
+        self.active.insert(environment, email.to_string());
+
+        Ok(())
+    }
+}
+
+pub fn load_credentials() -> Result<CredentialStore, Box<dyn std::error::Error>> {
+    let environment = settings().environment;
+    read_store(&get_environment_dir()?, keychain_key(environment).as_ref())
+}
+
+/// Encrypts the store with the keychain key of this environment, or writes it
+/// as a private plaintext file when there is no keychain
+pub fn save_credentials(store: &CredentialStore) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = settings().environment;
+    write_store(&get_environment_dir()?, store, keychain_key(environment).as_ref())
+}
+
+/// Reads the store in `dir`. With a keychain `key`, a plaintext store written
+/// while there was no keychain is encrypted and removed.
+fn read_store(dir: &Path, key: Option<&Key<Aes256Gcm>>) -> Result<CredentialStore, Box<dyn std::error::Error>> {
+    let plain_path = dir.join(CREDENTIALS_FILE);
+    let encrypted_path = dir.join(ENCRYPTED_CREDENTIALS_FILE);
+
+    if encrypted_path.exists() {
+        let data = fs::read(&encrypted_path)?;
+        let sealed = data
+            .strip_prefix(ENCRYPTED_MAGIC)
+            .ok_or("Encrypted credentials file is malformed, log in again")?;
+        let key = key.ok_or("The keychain holding the credentials key is unavailable")?;
+        return Ok(serde_json::from_slice(&decrypt(sealed, key)?)?);
+    }
+
+    if plain_path.exists() {
+        let store = serde_json::from_str(&fs::read_to_string(&plain_path)?)?;
+        if key.is_some() {
+            write_store(dir, &store, key)?;
+            info!("Moved saved credentials into the encrypted store");
+        }
+        return Ok(store);
+    }
+
+    Ok(CredentialStore::default())
+}
+
+fn write_store(
+    dir: &Path,
+    store: &CredentialStore,
+    key: Option<&Key<Aes256Gcm>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let plain_path = dir.join(CREDENTIALS_FILE);
+    let encrypted_path = dir.join(ENCRYPTED_CREDENTIALS_FILE);
+    let content = serde_json::to_vec_pretty(store)?;
+
+    match key {
+        Some(key) => {
+            let mut sealed = ENCRYPTED_MAGIC.to_vec();
+            sealed.extend(encrypt(&content, key)?);
+            write_atomic(&encrypted_path, &sealed)?;
+            remove_if_exists(&plain_path)?;
+        }
+        None => {
+            write_private(&plain_path, &content)?;
+            remove_if_exists(&encrypted_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The random key the store of `environment` is encrypted with, created on
+/// first use. `None` when there is no usable keychain.
+fn keychain_key(environment: Environment) -> Option<Key<Aes256Gcm>> {
+    match read_or_create_keychain_key(environment) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            info!("No keychain ({}), keeping credentials in a private file", e);
+            None
+        }
+    }
+}
+
+fn read_or_create_keychain_key(environment: Environment) -> Result<Key<Aes256Gcm>, Box<dyn std::error::Error>> {
+    let entry = keyring::Entry::new(KEYCHAIN_SERVICE, &format!("{}-credentials-key", environment))?;
+
+    let encoded = match entry.get_password() {
+        Ok(encoded) => encoded,
+        Err(keyring::Error::NoEntry) => {
+            entry.set_password(&BASE64.encode(Aes256Gcm::generate_key(OsRng)))?;
+            // Read it back, so a keychain that drops writes isn't trusted with the key
+            entry.get_password()?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let bytes = BASE64.decode(encoded.trim())?;
+    if bytes.len() != 32 {
+        return Err("the credentials key in the keychain is malformed".into());
+    }
+
+    Ok(*Key::<Aes256Gcm>::from_slice(&bytes))
+}
+
+/// Writes a file only the current user can read, atomically. On Windows the
+/// user's profile directory is already private to them.
+fn write_private(path: &Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let temp_path = temp_path_for(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp_path)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // mode() only applies to newly created files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        // FAT and some network mounts accept chmod but ignore it
+        if file.metadata()?.permissions().mode() & 0o077 != 0 {
+            drop(file);
+            let _ = fs::remove_file(&temp_path);
+            return Err("File permissions can't be restricted to the current user here, no credentials saved".into());
+        }
+    }
+
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+
+    commit_temp_file(&temp_path, path)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Layout: 12-byte nonce followed by the AES-256-GCM ciphertext
+fn encrypt(plaintext: &[u8], key: &Key<Aes256Gcm>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new(key);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Could not encrypt credentials")?;
+
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+
+    Ok(out)
+}
+
+fn decrypt(data: &[u8], key: &Key<Aes256Gcm>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if data.len() < NONCE_LEN {
+        return Err("Encrypted credentials file is truncated".into());
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(key);
+
+    // Also fails when the file was changed, or encrypted with another key
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Could not decrypt credentials, log in again".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::AuthTokens;
+
+    fn store() -> CredentialStore {
+        let mut store = CredentialStore::default();
+        store.upsert(StoredAccount {
+            email: "pilot@example.com".to_string(),
+            environment: Environment::Staging,
+            tokens: AuthTokens {
+                access_token: "access".to_string(),
+                refresh_token: Some("refresh".to_string()),
+                expires_at: Some(1_700_000_000),
+            },
+        });
+        store
+    }
+
+    fn active_email(store: &CredentialStore) -> Option<&str> {
+        store.active_account(Environment::Staging).map(|a| a.email.as_str())
+    }
+
+    #[test]
+    fn encrypted_store_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Aes256Gcm::generate_key(OsRng);
+
+        write_store(dir.path(), &store(), Some(&key)).unwrap();
+
+        let on_disk = fs::read(dir.path().join(ENCRYPTED_CREDENTIALS_FILE)).unwrap();
+        assert!(on_disk.starts_with(ENCRYPTED_MAGIC));
+        assert!(!on_disk.windows(6).any(|w| w == b"access"));
+        assert!(!dir.path().join(CREDENTIALS_FILE).exists());
+
+        let loaded = read_store(dir.path(), Some(&key)).unwrap();
+        assert_eq!(active_email(&loaded), Some("pilot@example.com"));
+    }
+
+    #[test]
+    fn rejects_tampered_or_foreign_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Aes256Gcm::generate_key(OsRng);
+        write_store(dir.path(), &store(), Some(&key)).unwrap();
+
+        let other_key = Aes256Gcm::generate_key(OsRng);
+        assert!(read_store(dir.path(), Some(&other_key)).is_err());
+        assert!(read_store(dir.path(), None).is_err());
+
+        let path = dir.path().join(ENCRYPTED_CREDENTIALS_FILE);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(read_store(dir.path(), Some(&key)).is_err());
+
+        // Nonce and ciphertext without the header aren't ours
+        fs::write(&path, &data[ENCRYPTED_MAGIC.len()..]).unwrap();
+        assert!(read_store(dir.path(), Some(&key)).is_err());
+    }
+
+    #[test]
+    fn plaintext_store_moves_into_the_keychain_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Aes256Gcm::generate_key(OsRng);
+        fs::write(dir.path().join(CREDENTIALS_FILE), serde_json::to_vec(&store()).unwrap()).unwrap();
+
+        let loaded = read_store(dir.path(), Some(&key)).unwrap();
+
+        assert_eq!(active_email(&loaded), Some("pilot@example.com"));
+        assert!(!dir.path().join(CREDENTIALS_FILE).exists());
+        assert_eq!(active_email(&read_store(dir.path(), Some(&key)).unwrap()), Some("pilot@example.com"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn without_a_keychain_the_store_is_a_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        write_store(dir.path(), &store(), None).unwrap();
+
+        let path = dir.path().join(CREDENTIALS_FILE);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!temp_path_for(&path).exists());
+        assert_eq!(active_email(&read_store(dir.path(), None).unwrap()), Some("pilot@example.com"));
+    }
+}
@@ -1,21 +1,50 @@
+use regex::Regex;
+use serde::Deserialize;
 use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// The cameras we support out of the box
+const BUILTIN_DEVICES: &str = include_str!("../assets/devices.toml");
+// Additions and overrides in ~/.openspace_sync
+const DEVICES_FILE: &str = "devices.toml";
+
+// Set by load_device_registry; the built-in devices alone until then
+static REGISTRY: OnceLock<DeviceRegistry> = OnceLock::new();
+
+fn registry() -> &'static DeviceRegistry {
+    REGISTRY.get_or_init(|| DeviceRegistry::parse(BUILTIN_DEVICES).expect("built-in device registry"))
+}
+
+/// Loads the built-in devices plus the overrides in `dir`/devices.toml, if
+/// there is one. Call once at startup, before any camera is looked up; an
+/// invalid devices.toml is an error rather than silently ignored.
+pub fn load_device_registry(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let registry = read_device_registry(dir)?;
+    REGISTRY.set(registry).map_err(|_| "Device registry is already loaded")?;
+
+    Ok(())
+}
+
+fn read_device_registry(dir: &Path) -> Result<DeviceRegistry, Box<dyn std::error::Error>> {
+    let builtin = DeviceRegistry::parse(BUILTIN_DEVICES).expect("built-in device registry");
+
+    Ok(match load_device_overrides(dir)? {
+        Some(overrides) => builtin.merge(overrides),
+        None => builtin,
+    })
+}
+
+/// Camera family, which decides behavior that can't be described as data:
+/// where to find a serial on the card and how files pair into captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum VendorType {
     Insta,
     Theta,
 }
 
-impl VendorType {
-    pub fn from_vendor_id(vendor_id: u16) -> Option<Self> {
-        match vendor_id {
-            1802 => Some(VendorType::Insta),
-            1482 => Some(VendorType::Theta),
-            _ => None,
-        }
-    }
-}
-
 impl fmt::Display for VendorType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -25,28 +54,363 @@ impl fmt::Display for VendorType {
     }
 }
 
+/// A kind of file a camera records, and the content type it is uploaded as
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MediaFileType {
+    pub extension: String,
+    pub content_type: String,
+}
+
+/**
+ * Data model for one `[[devices]]` entry of devices.toml
+*/
+#[derive(Debug, Clone, Deserialize)]
+struct DeviceSpecFile {
+    vendor: VendorType,
+    vendor_id: u16,
+    product_ids: Vec<u16>,
+    name: String,
+    model_name: String,
+    device_id_format: String,
+    media_folder: String,
+    media_files: Vec<MediaFileType>,
+    #[serde(default)]
+    file_name: Option<String>,
+    #[serde(default)]
+    split_lens_videos: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeviceRegistryFile {
+    #[serde(default)]
+    devices: Vec<DeviceSpecFile>,
+}
+
+/// A supported camera model
+#[derive(Debug)]
+struct DeviceSpec {
+    vendor: VendorType,
+    vendor_id: u16,
+    product_ids: Vec<u16>,
+    name: String,
+    model_name: String,
+    device_id_format: String,
+    media_folder: Regex,
+    media_files: Vec<MediaFileType>,
+    file_name: Option<Regex>,
+    split_lens_videos: bool,
+}
+
+impl TryFrom<DeviceSpecFile> for DeviceSpec {
+    type Error = String;
+
+    fn try_from(spec: DeviceSpecFile) -> Result<Self, Self::Error> {
+        if !spec.device_id_format.contains("{serial}") {
+            return Err(format!("device_id_format of {} has no {{serial}}", spec.name));
+        }
+        let media_folder =
+            Regex::new(&spec.media_folder).map_err(|e| format!("media_folder of {}: {}", spec.name, e))?;
+        let file_name = spec
+            .file_name
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| format!("file_name of {}: {}", spec.name, e))?;
+
+        Ok(Self {
+            vendor: spec.vendor,
+            vendor_id: spec.vendor_id,
+            product_ids: spec.product_ids,
+            name: spec.name,
+            model_name: spec.model_name,
+            device_id_format: spec.device_id_format,
+            media_folder,
+            media_files: spec.media_files,
+            file_name,
+            split_lens_videos: spec.split_lens_videos,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct DeviceRegistry {
+    devices: Vec<DeviceSpec>,
+}
+
+impl DeviceRegistry {
+    fn parse(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: DeviceRegistryFile = toml::from_str(content)?;
+        let devices = file
+            .devices
+            .into_iter()
+            .map(DeviceSpec::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { devices })
+    }
+
+    /// `overrides` take over any (vendor, product) pair they list
+    fn merge(mut self, overrides: DeviceRegistry) -> Self {
+        for spec in &overrides.devices {
+            for device in &mut self.devices {
+                if device.vendor_id == spec.vendor_id {
+                    device.product_ids.retain(|id| !spec.product_ids.contains(id));
+                }
+            }
+        }
+        self.devices.retain(|d| !d.product_ids.is_empty());
+        self.devices.extend(overrides.devices);
+
+        self
+    }
+
+    fn find(&self, vendor_id: u16, product_id: u16) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| d.vendor_id == vendor_id && d.product_ids.contains(&product_id))
+    }
+}
+
+fn load_device_overrides(dir: &Path) -> Result<Option<DeviceRegistry>, Box<dyn std::error::Error>> {
+    let path = dir.join(DEVICES_FILE);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let registry = DeviceRegistry::parse(&content).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    info!("Loaded {} device definitions from {}", registry.devices.len(), path.display());
+
+    Ok(Some(registry))
+}
+
+/// A camera model from the device registry
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum DeviceType {
-    Insta360OneX2,
-    ThetaZ1,
+pub struct DeviceType {
+    index: usize,
 }
 
 impl DeviceType {
-    pub fn from_product_id(product_id: u16) -> Option<Self> {
-        match product_id {
-            16422 => Some(DeviceType::Insta360OneX2),
-            16423 => Some(DeviceType::Insta360OneX2),
-            877 => Some(DeviceType::ThetaZ1),
-            _ => None,
+    /// Looks up a USB device; product IDs are only unique within a vendor
+    pub fn from_product_id(vendor_id: u16, product_id: u16) -> Option<Self> {
+        registry().find(vendor_id, product_id).map(|index| Self { index })
+    }
+
+    /// Every camera model in the registry
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..registry().devices.len()).map(|index| Self { index })
+    }
+
+    fn spec(&self) -> &'static DeviceSpec {
+        &registry().devices[self.index]
+    }
+
+    pub fn vendor(&self) -> VendorType {
+        self.spec().vendor
+    }
+
+    /// Model name as it appears in device IDs on the server
+    pub fn model_name(&self) -> &'static str {
+        &self.spec().model_name
+    }
+
+    /// Device ID for a camera of this type, given its already normalized serial
+    /// number, e.g. `Insta360 OneX2:sn:IXSE42ABCDEF`
+    pub fn device_id(&self, serial: &str) -> String {
+        self.spec()
+            .device_id_format
+            .replace("{model}", self.model_name())
+            .replace("{serial}", serial)
+    }
+
+    /// The serial number in a device ID of this type, `None` when the ID isn't
+    /// in this type's format
+    pub fn serial_from_device_id<'a>(&self, device_id: &'a str) -> Option<&'a str> {
+        let format = self.spec().device_id_format.replace("{model}", self.model_name());
+        let (prefix, suffix) = format.split_once("{serial}")?;
+
+        device_id.strip_prefix(prefix)?.strip_suffix(suffix)
+    }
+
+    /// The files this camera records that get synced
+    pub fn media_files(&self) -> &'static [MediaFileType] {
+        &self.spec().media_files
+    }
+
+    /// Whether 360 videos may be written as one file per lens, which then
+    /// only upload together
+    pub fn splits_lens_videos(&self) -> bool {
+        self.spec().split_lens_videos
+    }
+
+    /// Content type to upload a file with, or `None` when it isn't a recording
+    /// of this camera. `path` is `/`-separated and relative to the card root,
+    /// e.g. `DCIM/100RICOH/R0010012.JPG`.
+    pub fn content_type_for(&self, path: &str) -> Option<&'static str> {
+        let (folder, name) = path.rsplit_once('/')?;
+        if !self.spec().media_folder.is_match(folder) {
+            return None;
+        }
+
+        self.content_type_for_extension(name)
+    }
+
+    /// Like `content_type_for`, also taking files that were copied off the card
+    /// into some other folder layout, which are told by the way the camera
+    /// names them rather than by extension alone
+    pub fn content_type_for_copy(&self, path: &str) -> Option<&'static str> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        match &self.spec().file_name {
+            Some(file_name) if file_name.is_match(name) => self.content_type_for_extension(name),
+            _ => self.content_type_for(path),
         }
     }
+
+    fn content_type_for_extension(&self, filename: &str) -> Option<&'static str> {
+        let (_, extension) = filename.rsplit_once('.')?;
+        self.media_files()
+            .iter()
+            .find(|f| f.extension.eq_ignore_ascii_case(extension))
+            .map(|f| f.content_type.as_str())
+    }
 }
 
 impl fmt::Display for DeviceType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            DeviceType::Insta360OneX2 => write!(f, "Insta360 One X2"),
-            DeviceType::ThetaZ1 => write!(f, "Theta Z1"),
-        }
+        write!(f, "{}", self.spec().name)
+    }
+}
+
+/// Built-in models, for tests elsewhere in the crate
+#[cfg(test)]
+pub mod test_devices {
+    use super::DeviceType;
+
+    pub fn insta360_one_x2() -> DeviceType {
+        DeviceType::from_product_id(0x070a, 0x4026).unwrap()
+    }
+
+    pub fn theta_z1() -> DeviceType {
+        DeviceType::from_product_id(0x05ca, 0x036d).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_devices() {
+        let registry = DeviceRegistry::parse(BUILTIN_DEVICES).unwrap();
+
+        assert_eq!(registry.find(0x070a, 0x4026), registry.find(0x070a, 0x4027));
+        assert!(registry.find(0x05ca, 0x036d).is_some());
+        // Product IDs of one vendor don't match another vendor's devices
+        assert_eq!(registry.find(0x05ca, 0x4026), None);
+    }
+
+    #[test]
+    fn overrides_replace_builtin_products() {
+        let overrides = DeviceRegistry::parse(
+            r#"
+            [[devices]]
+            vendor = "insta"
+            vendor_id = 0x070a
+            product_ids = [0x4027, 0x4100]
+            name = "Insta360 X3"
+            model_name = "Insta360 X3"
+            device_id_format = "{model}:sn:{serial}"
+            media_folder = '^DCIM/Camera\d{2}$'
+            media_files = [{ extension = "insv", content_type = "video/insv" }]
+            "#,
+        )
+        .unwrap();
+        let registry = DeviceRegistry::parse(BUILTIN_DEVICES).unwrap().merge(overrides);
+
+        let x2 = registry.find(0x070a, 0x4026).unwrap();
+        let x3 = registry.find(0x070a, 0x4100).unwrap();
+        assert_eq!(registry.devices[x2].name, "Insta360 One X2");
+        assert_eq!(registry.devices[x3].name, "Insta360 X3");
+        assert_eq!(registry.find(0x070a, 0x4027), Some(x3));
+    }
+
+    #[test]
+    fn reads_overrides_from_the_given_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_device_registry(dir.path()).unwrap().devices.len(), 2);
+
+        fs::write(
+            dir.path().join(DEVICES_FILE),
+            r#"
+            [[devices]]
+            vendor = "theta"
+            vendor_id = 0x05ca
+            product_ids = [0x2527]
+            name = "Theta X"
+            model_name = "RICOH THETA X"
+            device_id_format = "{model}:sn:{serial}"
+            media_folder = '^DCIM/\d{3}RICOH$'
+            media_files = []
+            "#,
+        )
+        .unwrap();
+        assert!(read_device_registry(dir.path()).unwrap().find(0x05ca, 0x2527).is_some());
+
+        fs::write(dir.path().join(DEVICES_FILE), "[[devices]]\nvendor = \"canon\"\n").unwrap();
+        let error = read_device_registry(dir.path()).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid "), "{}", error);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_device_id_format_without_serial() {
+        let result = DeviceRegistry::parse(
+            r#"
+            [[devices]]
+            vendor = "theta"
+            vendor_id = 0x05ca
+            product_ids = [0x0999]
+            name = "Theta X"
+            model_name = "RICOH THETA X"
+            device_id_format = "{model}"
+            media_folder = '^DCIM/\d{3}RICOH$'
+            media_files = []
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn device_ids_and_content_types() {
+        let theta = test_devices::theta_z1();
+
+        assert_eq!(theta.device_id("10010123"), "RICOH THETA Z1:sn:10010123");
+        assert_eq!(theta.content_type_for("DCIM/100RICOH/R0010012.JPG"), Some("image/jpeg"));
+        assert_eq!(theta.content_type_for("DCIM/R0010012.JPG"), None);
+    }
+
+    #[test]
+    fn tells_copied_recordings_by_name() {
+        let theta = test_devices::theta_z1();
+        let insta = test_devices::insta360_one_x2();
+
+        assert_eq!(theta.content_type_for_copy("DCIM/100RICOH/THETA.JPG"), Some("image/jpeg"));
+        assert_eq!(theta.content_type_for_copy("site visit/R0010012.JPG"), Some("image/jpeg"));
+        // What a phone records
+        assert_eq!(theta.content_type_for_copy("DCIM/Camera/IMG_20240101_120000.jpg"), None);
+        assert_eq!(theta.content_type_for_copy("VID_20240101_120000.mp4"), None);
+        assert_eq!(insta.content_type_for_copy("copied/VID_20240101_120000_00_001.insv"), Some("video/insv"));
+        assert_eq!(insta.content_type_for_copy("copied/VID_20240101_120000_00_001.mp4"), None);
+    }
+
+    #[test]
+    fn reads_serials_from_device_ids() {
+        let theta = test_devices::theta_z1();
+
+        assert_eq!(theta.serial_from_device_id("RICOH THETA Z1:sn:10010123"), Some("10010123"));
+        assert_eq!(theta.serial_from_device_id("RICOH THETA Z1:sn:"), Some(""));
+        assert_eq!(theta.serial_from_device_id("Insta360 OneX2:sn:IXSE42ABCDEF"), None);
+        assert_eq!(theta.serial_from_device_id("10010123"), None);
+    }
+}
@@ -0,0 +1,12 @@
+#[macro_use]
+pub mod log;
+
+pub mod api;
+pub mod camera_fs;
+pub mod config;
+pub mod credentials;
+pub mod device_type;
+pub mod json;
+pub mod oauth_redirect;
+pub mod openspace;
+pub mod storage;
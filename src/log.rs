@@ -0,0 +1,43 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const STDOUT: u8 = 0;
+const STDERR: u8 = 1;
+const OFF: u8 = 2;
+
+static OUTPUT: AtomicU8 = AtomicU8::new(STDOUT);
+
+/// Where progress messages from `info!` go. Errors always go to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout,
+    // Keeps stdout free for machine readable output
+    Stderr,
+    Off,
+}
+
+pub fn set_log_output(output: LogOutput) {
+    let value = match output {
+        LogOutput::Stdout => STDOUT,
+        LogOutput::Stderr => STDERR,
+        LogOutput::Off => OFF,
+    };
+    OUTPUT.store(value, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn write_info(args: fmt::Arguments) {
+    match OUTPUT.load(Ordering::Relaxed) {
+        STDOUT => println!("{}", args),
+        STDERR => eprintln!("{}", args),
+        _ => {}
+    }
+}
+
+/// `println!` for progress messages, see `set_log_output`
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write_info(format_args!($($arg)*))
+    };
+}
@@ -1,20 +1,52 @@
-mod api;
-mod camera_fs;
-mod device_type;
-mod json;
-mod openspace;
-mod storage;
-
-use crate::openspace::upload_all_files::{upload_all_files, UploadEvent};
-use crate::storage::clear_skipped_files;
+use openspace_sync::api;
+use openspace_sync::camera_fs::camera::Camera;
+use openspace_sync::camera_fs::camera_finder::scan_for_camera_fs;
+use openspace_sync::camera_fs::hotplug::open_hotplug_source;
+use openspace_sync::config::{check_settings, settings, Environment};
+use openspace_sync::device_type::load_device_registry;
+use openspace_sync::openspace::auto_sync::{plugged_cameras, sync_plugged_camera};
+use openspace_sync::openspace::event_log::write_event_log;
+use openspace_sync::openspace::upload_all_files::{upload_all_files, upload_folder, SyncCheck, UploadEvent, UploadEvents};
+use openspace_sync::openspace::upload_control::UploadControl;
+use openspace_sync::storage::{clear_synced_files, get_storage_dir};
 use dioxus::prelude::*;
-use dioxus_desktop::tao;
-use std::collections::HashMap;
-use std::sync::mpsc;
+use dioxus_desktop::trayicon::init_tray_icon;
+use dioxus_desktop::trayicon::menu::{Menu, MenuItem, PredefinedMenuItem};
+use dioxus_desktop::{tao, use_tray_menu_event_handler, WindowCloseBehaviour};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 const MAIN_CSS: &str = include_str!("../assets/main.css");
+// Tray menu entry that brings the hidden window back
+const TRAY_SHOW_ID: &str = "show";
+
+// Held by the upload that is running, so another one waits for it to finish
+static UPLOAD_RUNNING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn exit_with_error(error: &str, file: &str) -> ! {
+    eprintln!("{}", error);
+    rfd::MessageDialog::new()
+        .set_level(rfd::MessageLevel::Error)
+        .set_title("OpenSpace Desktop Sync")
+        .set_description(format!("{}\n\nFix ~/.openspace_sync/{} and start the app again.", error, file))
+        .show();
+    std::process::exit(1);
+}
 
 fn main() {
+    // Stop before talking to the wrong backend
+    if let Err(e) = check_settings() {
+        exit_with_error(&e, "config.toml");
+    }
+    // Or before missing cameras someone added by hand
+    if let Err(e) = get_storage_dir().and_then(|dir| load_device_registry(&dir)) {
+        exit_with_error(&e.to_string(), "devices.toml");
+    }
+
     // Build a window configuration
     let window = tao::window::WindowBuilder::new()
         .with_inner_size(tao::dpi::LogicalSize::new(400.0, 600.0))
@@ -24,10 +56,18 @@ fn main() {
         .with_max_inner_size(tao::dpi::LogicalSize::new(400.0, 600.0))
         .with_resizable(true); // make it fixed size if you want
 
+    // With a tray icon, closing the window keeps auto-sync running
+    let close_behaviour = if settings().auto_sync.tray {
+        WindowCloseBehaviour::LastWindowHides
+    } else {
+        WindowCloseBehaviour::LastWindowExitsApp
+    };
+
     LaunchBuilder::new()
         .with_cfg(
             dioxus_desktop::Config::new()
                 .with_window(window)
+                .with_close_behaviour(close_behaviour)
                 .with_custom_head(format!(r#"<style>{}</style>"#, MAIN_CSS)),
         )
         .launch(App);
@@ -38,45 +78,187 @@ fn App() -> Element {
     rsx! {
         document::Link { rel: "stylesheet", href: MAIN_CSS }
 
+        if settings().auto_sync.tray {
+            TrayIcon {}
+        }
         Hero {}
     }
 }
 
+/// Keeps the app reachable from the system tray while its window is hidden
+#[component]
+fn TrayIcon() -> Element {
+    use_hook(|| {
+        let menu = Menu::new();
+        let show = MenuItem::with_id(TRAY_SHOW_ID, "Show OpenSpace Sync", true, None);
+        if let Err(e) = menu.append_items(&[&show, &PredefinedMenuItem::separator(), &PredefinedMenuItem::quit(None)]) {
+            eprintln!("Failed to build tray menu: {}", e);
+        }
+        init_tray_icon(menu, None);
+    });
+
+    use_tray_menu_event_handler(|event| {
+        if event.id.0 == TRAY_SHOW_ID {
+            let window = dioxus_desktop::window();
+            window.set_visible(true);
+            window.set_focus();
+        }
+    });
+
+    rsx! {}
+}
+
 #[derive(Clone, Debug)]
 pub struct UploadStatus {
+    pub device_id: String,
     pub filename: String,
     pub bytes_uploaded: i64,
     pub total_bytes: i64,
     pub percentage: f64,
-    pub status: String, // "uploading", "completed", "skipped", "failed"
+    pub parts_completed: i32,
+    pub num_parts: i32,
+    pub retries: u32,
+    pub last_retry_reason: String,
+    pub status: String, // "uploading", "completed", "skipped", "cancelled", "failed", "incomplete"
 }
 
 #[component]
 pub fn Hero() -> Element {
-    let device_id = use_signal(|| String::from("No camera connected"));
+    let cameras = use_signal(Vec::<Camera>::new);
+    let selected = use_signal(HashSet::<String>::new);
     let uploads = use_signal(|| HashMap::<String, UploadStatus>::new());
     let skipped_count = use_signal(|| 0usize);
     let is_uploading = use_signal(|| false);
+    let verify_with_server = use_signal(|| false);
+    // Controls the upload in flight, replaced for every upload
+    let upload_control = use_signal(UploadControl::default);
+    // Active account email, restored from the credential store on startup
+    let account = use_signal(api::restore_session);
+    let accounts = use_signal(api::saved_accounts);
+    let auth_status = use_signal(String::new);
+    let is_logging_in = use_signal(|| false);
+
+    let camera_summary = match cameras().as_slice() {
+        [] => String::from("No camera connected"),
+        [camera] => camera.device_id.clone(),
+        all => format!("{} cameras connected", all.len()),
+    };
 
     rsx! {
         div { id: "app",
-            div { id: "header", span { "OpenSpace Desktop Sync" } }
+            div { id: "header",
+                span { "OpenSpace Desktop Sync" }
+                if settings().environment != Environment::Prod {
+                    span { class: "environment-badge", "{settings().environment}" }
+                }
+            }
             div { id: "content",
-                { build_content(device_id, uploads, skipped_count, is_uploading) }
+                { build_content(cameras, selected, uploads, skipped_count, is_uploading, verify_with_server, upload_control) }
             }
             div { id: "footer",
-                div { id: "footer-bar", p { "{device_id}" }}
+                div { id: "footer-bar", p { "{camera_summary}" }}
                 div { id: "footer-bar", p { "Le App is Updated" }}
+                div { id: "footer-bar",
+                    if accounts().len() > 1 {
+                        select {
+                            class: "account-select",
+                            disabled: is_logging_in(),
+                            onchange: move |e| switch_account(e.value(), account, auth_status),
+                            for email in accounts() {
+                                option {
+                                    value: "{email}",
+                                    selected: account().as_deref() == Some(email.as_str()),
+                                    "{email}"
+                                }
+                            }
+                        }
+                    } else {
+                        p { {account().unwrap_or_else(|| String::from("Not logged in"))} }
+                    }
+                    button {
+                        class: "button button-small",
+                        disabled: is_logging_in(),
+                        onclick: move |_| {
+                            spawn(async move {
+                                start_login(account, accounts, auth_status, is_logging_in).await;
+                            });
+                        },
+                        if is_logging_in() {
+                            "Logging in..."
+                        } else if account().is_some() {
+                            "Add Account"
+                        } else {
+                            "Log In"
+                        }
+                    }
+                    if account().is_some() {
+                        button {
+                            class: "button button-small",
+                            disabled: is_logging_in(),
+                            onclick: move |_| log_out(account, accounts, auth_status),
+                            "Log Out"
+                        }
+                    }
+                    p { "{auth_status}" }
+                }
             }
         }
     }
 }
 
-fn render_upload_item(filename: &str, upload: &UploadStatus) -> Element {
+fn format_bytes(bytes: u64) -> String {
+    const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+    format!("{:.1} GB", bytes as f64 / GB)
+}
+
+fn render_camera_item(camera: &Camera, mut selected: Signal<HashSet<String>>, is_uploading: bool) -> Element {
+    let device_id = camera.device_id.clone();
+    let is_selected = selected().contains(&device_id);
+    let free_space = match (camera.free_space(), camera.capacity()) {
+        (Some(free), Some(capacity)) => format!("{} free of {}", format_bytes(free), format_bytes(capacity)),
+        (Some(free), None) => format!("{} free", format_bytes(free)),
+        _ => String::from("free space unknown"),
+    };
+    let volumes = camera
+        .volumes
+        .iter()
+        .map(|v| match &v.mount_point {
+            Some(mount_point) => mount_point.display().to_string(),
+            None => v.name.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    rsx! {
+        label {
+            key: "{camera.device_id}",
+            class: "camera-item",
+            input {
+                r#type: "checkbox",
+                checked: is_selected,
+                disabled: is_uploading,
+                onchange: move |_| {
+                    let mut current_selected = selected();
+                    if !current_selected.remove(&device_id) {
+                        current_selected.insert(device_id.clone());
+                    }
+                    selected.set(current_selected);
+                },
+            }
+            div {
+                p { class: "camera-name", "{camera.name}" }
+                p { class: "camera-details", "{camera.device_id}" }
+                p { class: "camera-details", "{camera.fs_strategy}: {volumes} · {free_space}" }
+            }
+        }
+    }
+}
+
+fn render_upload_item(filename: &str, upload: &UploadStatus, control: Signal<UploadControl>) -> Element {
     let status_class = match upload.status.as_str() {
         "completed" => "status-completed",
-        "skipped" => "status-skipped",
-        _ if upload.status.starts_with("failed") => "status-failed",
+        "skipped" | "cancelled" => "status-skipped",
+        _ if upload.status.starts_with("failed") || upload.status.starts_with("incomplete") => "status-failed",
         _ => "status-uploading",
     };
 
@@ -84,15 +266,35 @@ fn render_upload_item(filename: &str, upload: &UploadStatus) -> Element {
         div {
             key: "{filename}",
             class: "upload-item",
-            p { class: "upload-filename", "{upload.filename}" }
+            div { class: "upload-header",
+                p { class: "upload-filename", "{upload.filename}" }
+                if upload.status == "uploading" {
+                    button {
+                        class: "button button-small",
+                        onclick: {
+                            let (device_id, filename) = (upload.device_id.clone(), upload.filename.clone());
+                            move |_| control.read().skip_file(&device_id, &filename)
+                        },
+                        "Skip"
+                    }
+                }
+            }
             p {
                 class: "upload-status {status_class}",
                 "Status: {upload.status}"
             }
+            if upload.retries > 0 {
+                p { class: "upload-progress-text", "Retries: {upload.retries} ({upload.last_retry_reason})" }
+            }
             if upload.status == "uploading" {
                 p { class: "upload-progress-text",
                     "{upload.bytes_uploaded} / {upload.total_bytes} bytes ({upload.percentage:.1}%)"
                 }
+                if upload.num_parts > 1 {
+                    p { class: "upload-progress-text",
+                        "{upload.parts_completed} of {upload.num_parts} parts"
+                    }
+                }
                 div { class: "progress-bar-container",
                     div {
                         class: "progress-bar-fill",
@@ -104,116 +306,445 @@ fn render_upload_item(filename: &str, upload: &UploadStatus) -> Element {
     }
 }
 
+// Filenames repeat across cameras, so uploads are keyed by device and filename
+fn upload_key(device_id: &str, filename: &str) -> String {
+    format!("{}/{}", device_id, filename)
+}
+
 fn handle_upload_event(
     event: UploadEvent,
-    mut device_id: Signal<String>,
+    mut cameras: Signal<Vec<Camera>>,
     mut uploads: Signal<HashMap<String, UploadStatus>>,
     mut skipped_count: Signal<usize>,
 ) {
     match event {
-        UploadEvent::CameraFound(dev_id) => {
-            device_id.set(dev_id);
+        UploadEvent::CameraFound(camera) => {
+            let mut current_cameras = cameras();
+            match current_cameras.iter_mut().find(|c| c.device_id == camera.device_id) {
+                Some(existing) => *existing = camera,
+                None => current_cameras.push(camera),
+            }
+            cameras.set(current_cameras);
         }
-        UploadEvent::FileStarted { filename, total_bytes } => {
+        UploadEvent::FileStarted { device_id, filename, total_bytes } => {
             let mut current_uploads = uploads();
-            current_uploads.insert(filename.clone(), UploadStatus {
+            current_uploads.insert(upload_key(&device_id, &filename), UploadStatus {
+                device_id: device_id.clone(),
                 filename: filename.clone(),
                 bytes_uploaded: 0,
                 total_bytes,
                 percentage: 0.0,
+                parts_completed: 0,
+                num_parts: 0,
+                retries: 0,
+                last_retry_reason: String::new(),
                 status: "uploading".to_string(),
             });
             uploads.set(current_uploads);
         }
-        UploadEvent::FileProgress { filename, bytes_uploaded, total_bytes } => {
+        UploadEvent::FileProgress { device_id, filename, bytes_uploaded, total_bytes, parts_completed, num_parts } => {
             let mut current_uploads = uploads();
-            if let Some(upload) = current_uploads.get_mut(&filename) {
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
                 upload.bytes_uploaded = bytes_uploaded;
+                upload.parts_completed = parts_completed;
+                upload.num_parts = num_parts;
                 upload.percentage = (bytes_uploaded as f64 / total_bytes as f64) * 100.0;
             }
             uploads.set(current_uploads);
         }
-        UploadEvent::FileSkipped { filename } => {
+        UploadEvent::FileRetrying { device_id, filename, retries, reason } => {
             let mut current_uploads = uploads();
-            if let Some(upload) = current_uploads.get_mut(&filename) {
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
+                upload.retries = retries;
+                upload.last_retry_reason = reason;
+            }
+            uploads.set(current_uploads);
+        }
+        UploadEvent::FileSkipped { device_id, filename } => {
+            let mut current_uploads = uploads();
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
                 upload.status = "skipped".to_string();
             }
             uploads.set(current_uploads);
             skipped_count.set(skipped_count() + 1);
         }
-        UploadEvent::FileCompleted { filename } => {
+        UploadEvent::FileCompleted { device_id, filename } => {
             let mut current_uploads = uploads();
-            if let Some(upload) = current_uploads.get_mut(&filename) {
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
                 upload.status = "completed".to_string();
                 upload.percentage = 100.0;
             }
             uploads.set(current_uploads);
         }
-        UploadEvent::FileFailed { filename, error } => {
+        UploadEvent::FileFailed { device_id, filename, error } => {
             let mut current_uploads = uploads();
-            if let Some(upload) = current_uploads.get_mut(&filename) {
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
                 upload.status = format!("failed: {}", error);
             }
             uploads.set(current_uploads);
         }
+        UploadEvent::FileCancelled { device_id, filename } => {
+            let mut current_uploads = uploads();
+            if let Some(upload) = current_uploads.get_mut(&upload_key(&device_id, &filename)) {
+                upload.status = "cancelled".to_string();
+            }
+            uploads.set(current_uploads);
+        }
+        UploadEvent::CaptureIncomplete { device_id, capture_id, missing } => {
+            let mut current_uploads = uploads();
+            current_uploads.insert(upload_key(&device_id, &capture_id), UploadStatus {
+                device_id: device_id.clone(),
+                filename: format!("Capture {}", capture_id),
+                bytes_uploaded: 0,
+                total_bytes: 0,
+                percentage: 0.0,
+                parts_completed: 0,
+                num_parts: 0,
+                retries: 0,
+                last_retry_reason: String::new(),
+                status: format!("incomplete, missing {}", missing.join(", ")),
+            });
+            uploads.set(current_uploads);
+        }
     }
 }
 
+async fn start_login(
+    mut account: Signal<Option<String>>,
+    mut accounts: Signal<Vec<String>>,
+    mut auth_status: Signal<String>,
+    mut is_logging_in: Signal<bool>,
+) {
+    is_logging_in.set(true);
+    auth_status.set(String::from("Waiting for browser login..."));
+
+    match api::login().await {
+        Ok(logged_in) => {
+            account.set(Some(logged_in.email));
+            accounts.set(api::saved_accounts());
+            auth_status.set(String::new());
+        }
+        Err(e) => {
+            eprintln!("Login failed: {}", e);
+            auth_status.set(format!("Login failed: {}", e));
+        }
+    }
+
+    is_logging_in.set(false);
+}
+
+fn log_out(mut account: Signal<Option<String>>, mut accounts: Signal<Vec<String>>, mut auth_status: Signal<String>) {
+    match api::logout() {
+        Ok(next) => {
+            account.set(next);
+            accounts.set(api::saved_accounts());
+            auth_status.set(String::new());
+        }
+        Err(e) => {
+            eprintln!("Logout failed: {}", e);
+            auth_status.set(format!("Logout failed: {}", e));
+        }
+    }
+}
+
+fn switch_account(email: String, mut account: Signal<Option<String>>, mut auth_status: Signal<String>) {
+    match api::switch_account(&email) {
+        Ok(()) => {
+            account.set(Some(email));
+            auth_status.set(String::new());
+        }
+        Err(e) => {
+            eprintln!("Could not switch account: {}", e);
+            auth_status.set(format!("Could not switch account: {}", e));
+        }
+    }
+}
+
+async fn scan_cameras(mut cameras: Signal<Vec<Camera>>, mut selected: Signal<HashSet<String>>) {
+    // Detection shells out to system tools, keep it off the UI thread
+    let found = tokio::task::spawn_blocking(scan_for_camera_fs)
+        .await
+        .unwrap_or_default();
+
+    // Newly scanned cameras start out selected
+    selected.set(found.iter().map(|c| c.device_id.clone()).collect());
+    cameras.set(found);
+}
+
 async fn start_upload_process(
-    mut device_id: Signal<String>,
+    cameras: Signal<Vec<Camera>>,
+    selected: Signal<HashSet<String>>,
+    uploads: Signal<HashMap<String, UploadStatus>>,
+    skipped_count: Signal<usize>,
+    is_uploading: Signal<bool>,
+    verify_with_server: Signal<bool>,
+    upload_control: Signal<UploadControl>,
+) {
+    // Before the first scan there is nothing to choose from, so sync everything
+    let selection: Option<Vec<String>> = if cameras().is_empty() {
+        None
+    } else {
+        Some(selected().into_iter().collect())
+    };
+    let check = if verify_with_server() { SyncCheck::Server } else { SyncCheck::Local };
+
+    let job = move |events, control: UploadControl| async move {
+        upload_all_files(selection.as_deref(), settings().upload.concurrency(), check, events, &control)
+            .await
+            .map_err(|e| e.to_string())
+    };
+    if let Err(e) = run_upload(job, cameras, uploads, skipped_count, is_uploading, upload_control).await {
+        eprintln!("Upload failed: {}", e);
+    }
+}
+
+/// Asks for a folder and uploads it; returns how that went, or `None` when
+/// no folder was picked
+async fn upload_picked_folder(
+    device_id: String,
+    cameras: Signal<Vec<Camera>>,
+    uploads: Signal<HashMap<String, UploadStatus>>,
+    skipped_count: Signal<usize>,
+    is_uploading: Signal<bool>,
+    upload_control: Signal<UploadControl>,
+    check: SyncCheck,
+) -> Option<String> {
+    let folder = rfd::AsyncFileDialog::new()
+        .set_title("Folder with recordings to upload")
+        .pick_folder()
+        .await?;
+    let folder = folder.path().to_path_buf();
+    let device_id = Some(device_id.trim().to_string()).filter(|id| !id.is_empty());
+
+    let job = {
+        let folder = folder.clone();
+        move |events, control: UploadControl| async move {
+            let concurrency = settings().upload.concurrency();
+            upload_folder(&folder, device_id.as_deref(), concurrency, check, events, &control)
+                .await
+                .map_err(|e| e.to_string())
+        }
+    };
+
+    match run_upload(job, cameras, uploads, skipped_count, is_uploading, upload_control).await {
+        Ok(()) => Some(format!("Finished {}", folder.display())),
+        Err(e) => {
+            eprintln!("Folder upload failed: {}", e);
+            Some(e)
+        }
+    }
+}
+
+/// Runs an upload as its own task and applies its events to the UI as they
+/// arrive, until it finishes. The events are also written to the event log.
+/// An upload already running is finished first.
+async fn run_upload<F, Fut>(
+    job: F,
+    cameras: Signal<Vec<Camera>>,
     mut uploads: Signal<HashMap<String, UploadStatus>>,
     mut skipped_count: Signal<usize>,
     mut is_uploading: Signal<bool>,
-) {
+    mut upload_control: Signal<UploadControl>,
+) -> Result<(), String>
+where
+    F: FnOnce(UploadEvents, UploadControl) -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let _running = UPLOAD_RUNNING.lock().await;
     is_uploading.set(true);
     uploads.set(HashMap::new());
     skipped_count.set(0);
+    let control = UploadControl::default();
+    upload_control.set(control.clone());
 
-    // Create channel for progress updates
-    let (tx, rx) = mpsc::channel();
+    // Subscribe before the job starts so no event is missed; the job owns the
+    // only sender, so both streams end when it does
+    let events = UploadEvents::default();
+    let mut updates = pin!(events.stream());
+    tokio::spawn(write_event_log(events.stream()));
+    let upload_task = tokio::spawn(job(events, control));
 
-    // Spawn upload in background OS thread
-    std::thread::spawn(move || {
-        if let Err(e) = upload_all_files(Some(tx)) {
-            eprintln!("Upload failed: {}", e);
+    while let Some(event) = updates.next().await {
+        handle_upload_event(event, cameras, uploads, skipped_count);
+    }
+
+    is_uploading.set(false);
+
+    upload_task.await.unwrap_or_else(|_| Err("Upload task panicked".to_string()))
+}
+
+/// Watches for cameras being plugged in and uploads each one as the Upload
+/// Files button would, waiting for any upload already running. Returns the
+/// flag that stops the watcher.
+fn start_auto_sync(
+    cameras: Signal<Vec<Camera>>,
+    uploads: Signal<HashMap<String, UploadStatus>>,
+    skipped_count: Signal<usize>,
+    is_uploading: Signal<bool>,
+    verify_with_server: Signal<bool>,
+    upload_control: Signal<UploadControl>,
+) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let plugged = plugged_cameras(open_hotplug_source(), stop.clone());
+
+    let sync_stop = stop.clone();
+    spawn(async move {
+        let mut plugged = pin!(plugged);
+        while let Some(device_type) = plugged.next().await {
+            let check = if verify_with_server() { SyncCheck::Server } else { SyncCheck::Local };
+            let stop = sync_stop.clone();
+            let job = move |events, control: UploadControl| async move {
+                let concurrency = settings().upload.concurrency();
+                sync_plugged_camera(device_type, concurrency, check, events, &control, stop)
+                    .await
+                    .map_err(|e| e.to_string())
+            };
+            if let Err(e) = run_upload(job, cameras, uploads, skipped_count, is_uploading, upload_control).await {
+                eprintln!("Auto-sync of {} failed: {}", device_type, e);
+            }
         }
-        // tx is dropped here when the thread exits, disconnecting the channel
     });
 
-    // Process events from upload thread in async context
-    loop {
-        match rx.try_recv() {
-            Ok(event) => {
-                handle_upload_event(event, device_id, uploads, skipped_count);
+    stop
+}
+
+/// Switch for auto-sync, which starts out on when config.toml enables it
+#[component]
+fn AutoSync(
+    cameras: Signal<Vec<Camera>>,
+    uploads: Signal<HashMap<String, UploadStatus>>,
+    skipped_count: Signal<usize>,
+    is_uploading: Signal<bool>,
+    verify_with_server: Signal<bool>,
+    upload_control: Signal<UploadControl>,
+) -> Element {
+    // Stops the running watcher, None while auto-sync is off
+    let mut watcher_stop = use_signal(move || {
+        settings()
+            .auto_sync
+            .enabled
+            .then(|| start_auto_sync(cameras, uploads, skipped_count, is_uploading, verify_with_server, upload_control))
+    });
+
+    rsx! {
+        label { class: "verify-option",
+            input {
+                r#type: "checkbox",
+                checked: watcher_stop().is_some(),
+                onchange: move |_| match watcher_stop() {
+                    Some(stop) => {
+                        stop.store(true, Ordering::Relaxed);
+                        watcher_stop.set(None);
+                    }
+                    None => {
+                        let stop = start_auto_sync(
+                            cameras,
+                            uploads,
+                            skipped_count,
+                            is_uploading,
+                            verify_with_server,
+                            upload_control,
+                        );
+                        watcher_stop.set(Some(stop));
+                    }
+                },
             }
-            Err(mpsc::TryRecvError::Empty) => {
-                // No more events yet, wait a bit
-                // Use tokio sleep since we're in an async context
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            "Upload automatically when a camera is plugged in"
+        }
+    }
+}
+
+/// Uploads a folder picked by the user, for recordings that aren't on a connected
+/// camera. The device ID can be left empty for cameras that write their serial
+/// number into their files.
+#[component]
+fn FolderUpload(
+    cameras: Signal<Vec<Camera>>,
+    uploads: Signal<HashMap<String, UploadStatus>>,
+    skipped_count: Signal<usize>,
+    is_uploading: Signal<bool>,
+    verify_with_server: Signal<bool>,
+    upload_control: Signal<UploadControl>,
+) -> Element {
+    let mut device_id = use_signal(String::new);
+    let mut folder_status = use_signal(String::new);
+
+    rsx! {
+        div { class: "folder-upload",
+            input {
+                class: "text-input",
+                r#type: "text",
+                placeholder: "Device ID (optional)",
+                value: "{device_id}",
+                disabled: is_uploading(),
+                oninput: move |e| device_id.set(e.value()),
             }
-            Err(mpsc::TryRecvError::Disconnected) => {
-                // Channel closed, upload finished
-                break;
+            button {
+                class: "button",
+                disabled: is_uploading(),
+                onclick: move |_| {
+                    let check = if verify_with_server() { SyncCheck::Server } else { SyncCheck::Local };
+                    spawn(async move {
+                        let status = upload_picked_folder(
+                            device_id(),
+                            cameras,
+                            uploads,
+                            skipped_count,
+                            is_uploading,
+                            upload_control,
+                            check
+                        ).await;
+                        if let Some(status) = status {
+                            folder_status.set(status);
+                        }
+                    });
+                },
+                "Upload Folder..."
             }
         }
+        if !folder_status().is_empty() {
+            p { class: "folder-status", "{folder_status}" }
+        }
     }
-
-    is_uploading.set(false);
 }
 
 fn build_content(
-    mut device_id: Signal<String>,
+    cameras: Signal<Vec<Camera>>,
+    selected: Signal<HashSet<String>>,
     mut uploads: Signal<HashMap<String, UploadStatus>>,
     mut skipped_count: Signal<usize>,
     mut is_uploading: Signal<bool>,
+    mut verify_with_server: Signal<bool>,
+    mut upload_control: Signal<UploadControl>,
 ) -> Element {
     rsx! {
         div { class: "content-container",
+            // Camera list
+            if !cameras().is_empty() {
+                div { class: "camera-list-container",
+                    for camera in cameras().iter() {
+                        { render_camera_item(camera, selected, is_uploading()) }
+                    }
+                }
+            }
+
+            // Scan button
+            button {
+                class: "button",
+                disabled: is_uploading(),
+                onclick: move |_| {
+                    spawn(async move {
+                        scan_cameras(cameras, selected).await;
+                    });
+                },
+                "Scan for Cameras"
+            }
+
             // Upload list
             if !uploads().is_empty() {
                 div { class: "upload-list-container",
-                    for (filename, upload) in uploads().iter() {
-                        { render_upload_item(filename, upload) }
+                    for (key, upload) in uploads().iter() {
+                        { render_upload_item(key, upload, upload_control) }
                     }
                 }
             }
@@ -223,32 +754,87 @@ fn build_content(
                 p { class: "skipped-count", "Total skipped files: {skipped_count()}" }
             }
 
+            // Re-check files the local sync state says are uploaded
+            label { class: "verify-option",
+                input {
+                    r#type: "checkbox",
+                    checked: verify_with_server(),
+                    disabled: is_uploading(),
+                    onchange: move |_| verify_with_server.set(!verify_with_server()),
+                }
+                "Verify uploaded files with server"
+            }
+
             // Upload button
             button {
                 class: "button",
-                disabled: is_uploading(),
+                disabled: is_uploading() || (!cameras().is_empty() && selected().is_empty()),
                 onclick: move |_| {
                     spawn(async move {
                         start_upload_process(
-                            device_id,
+                            cameras,
+                            selected,
                             uploads,
                             skipped_count,
-                            is_uploading
+                            is_uploading,
+                            verify_with_server,
+                            upload_control
                         ).await;
                     });
                 },
-                if is_uploading() { "Uploading..." } else { "Upload Files" }
+                if !is_uploading() {
+                    "Upload Files"
+                } else if upload_control.read().is_paused() {
+                    "Paused"
+                } else {
+                    "Uploading..."
+                }
             }
 
+            // Controls for the upload in flight; parts already being sent finish first
+            if is_uploading() {
+                div { class: "upload-controls",
+                    button {
+                        class: "button",
+                        disabled: upload_control.read().is_cancelled(),
+                        onclick: move |_| {
+                            let control = upload_control.write();
+                            if control.is_paused() {
+                                control.resume();
+                            } else {
+                                control.pause();
+                            }
+                        },
+                        if upload_control.read().is_paused() { "Resume" } else { "Pause" }
+                    }
+                    button {
+                        class: "button button-danger",
+                        disabled: upload_control.read().is_cancelled(),
+                        onclick: move |_| upload_control.write().cancel(),
+                        if upload_control.read().is_cancelled() { "Cancelling..." } else { "Cancel All" }
+                    }
+                }
+            }
+
+            // Upload from a folder instead of a camera
+            FolderUpload { cameras, uploads, skipped_count, is_uploading, verify_with_server, upload_control }
+
+            // Upload whenever a camera is plugged in
+            AutoSync { cameras, uploads, skipped_count, is_uploading, verify_with_server, upload_control }
+
             // Clear cache button
             button {
                 class: "button button-danger",
                 onclick: move |_| {
-                    if let Err(e) = clear_skipped_files() {
-                        eprintln!("Failed to clear cache: {}", e);
-                    } else {
-                        println!("Cache cleared successfully");
-                    }
+                    spawn(async move {
+                        // Off the UI thread, the database may be busy
+                        let cleared = tokio::task::spawn_blocking(|| clear_synced_files().map_err(|e| e.to_string()));
+                        match cleared.await {
+                            Ok(Ok(())) => println!("Cache cleared successfully"),
+                            Ok(Err(e)) => eprintln!("Failed to clear cache: {}", e),
+                            Err(e) => eprintln!("Failed to clear cache: {}", e),
+                        }
+                    });
                 },
                 "Clear Cache"
             }
@@ -0,0 +1,98 @@
+use oauth2::url::Url;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const CALLBACK_PATH: &str = "/callback";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SUCCESS_PAGE: &str = "<html><body><h2>Logged in to OpenSpace Desktop Sync</h2><p>You can close this window.</p></body></html>";
+const FAILURE_PAGE: &str = "<html><body><h2>Login failed</h2><p>Return to OpenSpace Desktop Sync and try again.</p></body></html>";
+
+/// Waits on a loopback listener until the browser is redirected back with an
+/// authorization code, and returns that code. The `state` parameter must match
+/// the CSRF token we sent. Requests for other paths (e.g. /favicon.ico) are
+/// answered with 404 and otherwise ignored.
+pub fn wait_for_redirect(
+    listener: TcpListener,
+    expected_state: &str,
+    timeout: Duration,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => match handle_connection(stream, expected_state) {
+                Ok(Some(result)) => return result.map_err(|e| e.into()),
+                Ok(None) => {}
+                Err(e) => eprintln!("Error reading login redirect: {}", e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err("Timed out waiting for the browser login".into());
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Returns `None` when the request wasn't the OAuth callback
+fn handle_connection(mut stream: TcpStream, expected_state: &str) -> std::io::Result<Option<Result<String, String>>> {
+    // Accepted sockets inherit non-blocking mode on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    // "GET /callback?code=...&state=... HTTP/1.1"
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let Ok(url) = Url::parse(&format!("http://127.0.0.1{}", target)) else {
+        respond(&mut stream, "400 Bad Request", FAILURE_PAGE)?;
+        return Ok(None);
+    };
+
+    if url.path() != CALLBACK_PATH {
+        respond(&mut stream, "404 Not Found", "")?;
+        return Ok(None);
+    }
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+
+    let result = if let Some(error) = param("error") {
+        let description = param("error_description").unwrap_or_default();
+        Err(format!("Login was rejected: {} {}", error, description).trim().to_string())
+    } else if param("state").as_deref() != Some(expected_state) {
+        Err("Login redirect had an unexpected state, possible CSRF".to_string())
+    } else {
+        param("code").ok_or_else(|| "Login redirect did not include a code".to_string())
+    };
+
+    match result {
+        Ok(_) => respond(&mut stream, "200 OK", SUCCESS_PAGE)?,
+        Err(_) => respond(&mut stream, "400 Bad Request", FAILURE_PAGE)?,
+    }
+
+    Ok(Some(result))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
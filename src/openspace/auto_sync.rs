@@ -0,0 +1,184 @@
+use crate::camera_fs::camera::Camera;
+use crate::camera_fs::camera_finder::scan_for_camera_fs;
+use crate::camera_fs::hotplug::{HotplugEvent, HotplugSource};
+use crate::device_type::DeviceType;
+use crate::openspace::upload_all_files::{upload_all_files, SyncCheck, UploadConcurrency, UploadEvents};
+use crate::openspace::upload_control::UploadControl;
+use futures::stream::{self, Stream, StreamExt};
+use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// How long a camera gets after being plugged in to show up as a mounted
+// volume or MTP device; mounting and switching USB modes take a few seconds
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+const SETTLE_INTERVAL: Duration = Duration::from_secs(2);
+// How often a waiting watcher checks whether it was stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Syncs every supported camera that gets plugged in, one at a time, until
+/// `stop` is set. Cameras already connected are left alone.
+pub async fn auto_sync(
+    source: Box<dyn HotplugSource>,
+    concurrency: UploadConcurrency,
+    check: SyncCheck,
+    events: UploadEvents,
+    stop: Arc<AtomicBool>,
+) {
+    let mut plugged = pin!(plugged_cameras(source, stop.clone()));
+
+    while let Some(device_type) = plugged.next().await {
+        // The headless watcher has no controls, its uploads run to the end
+        let control = UploadControl::default();
+        let result = sync_plugged_camera(device_type, concurrency, check, events.clone(), &control, stop.clone()).await;
+        if let Err(e) = result {
+            eprintln!("Auto-sync of {} failed: {}", device_type, e);
+        }
+    }
+}
+
+/// Supported cameras as they get plugged in, until `stop` is set. Hotplug
+/// sources block, so this one is read on its own thread.
+pub fn plugged_cameras(mut source: Box<dyn HotplugSource>, stop: Arc<AtomicBool>) -> impl Stream<Item = DeviceType> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    std::thread::spawn(move || {
+        let result = watch_hotplug(source.as_mut(), &stop, |device_type| {
+            let _ = tx.send(device_type);
+        });
+        if let Err(e) = result {
+            eprintln!("Stopped watching for cameras: {}", e);
+        }
+    });
+
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|device_type| (device_type, rx)) })
+}
+
+/// Calls `on_added` for every supported camera plugged in, until `stop` is set
+fn watch_hotplug(
+    source: &mut dyn HotplugSource,
+    stop: &AtomicBool,
+    mut on_added: impl FnMut(DeviceType),
+) -> io::Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        match source.next_event(STOP_CHECK_INTERVAL)? {
+            Some(HotplugEvent::Added(device_type)) => {
+                info!("{} plugged in", device_type);
+                on_added(device_type);
+            }
+            Some(HotplugEvent::Removed(device_type)) => info!("{} unplugged", device_type),
+            None => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits for a camera that was just plugged in to become readable, then
+/// syncs every connected camera of its type
+pub async fn sync_plugged_camera(
+    device_type: DeviceType,
+    concurrency: UploadConcurrency,
+    check: SyncCheck,
+    events: UploadEvents,
+    control: &UploadControl,
+    stop: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cameras =
+        tokio::task::spawn_blocking(move || wait_for_cameras(device_type, scan_for_camera_fs, SETTLE_INTERVAL, &stop))
+            .await?;
+    if cameras.is_empty() {
+        return Err(format!("{} didn't show up as storage within {:?}", device_type, SETTLE_TIMEOUT).into());
+    }
+
+    let device_ids: Vec<String> = cameras.into_iter().map(|c| c.device_id).collect();
+    upload_all_files(Some(&device_ids), concurrency, check, events, control).await
+}
+
+fn wait_for_cameras(
+    device_type: DeviceType,
+    scan: impl Fn() -> Vec<Camera>,
+    interval: Duration,
+    stop: &AtomicBool,
+) -> Vec<Camera> {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+
+    loop {
+        let cameras: Vec<Camera> = scan().into_iter().filter(|c| c.device_type == device_type).collect();
+        if !cameras.is_empty() || Instant::now() >= deadline || stop.load(Ordering::Relaxed) {
+            return cameras;
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_fs::camera::FileStrategy;
+    use crate::device_type::test_devices;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    /// Scripted events; stops the watcher once they run out
+    struct FakeSource<'a> {
+        events: VecDeque<Option<HotplugEvent>>,
+        stop: &'a AtomicBool,
+    }
+
+    impl HotplugSource for FakeSource<'_> {
+        fn next_event(&mut self, _timeout: Duration) -> io::Result<Option<HotplugEvent>> {
+            let event = self.events.pop_front().flatten();
+            if self.events.is_empty() {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+            Ok(event)
+        }
+    }
+
+    #[test]
+    fn reports_plugged_in_cameras() {
+        let x2 = test_devices::insta360_one_x2();
+        let z1 = test_devices::theta_z1();
+        let stop = AtomicBool::new(false);
+        let mut source = FakeSource {
+            events: VecDeque::from([
+                Some(HotplugEvent::Added(x2)),
+                None,
+                Some(HotplugEvent::Removed(x2)),
+                Some(HotplugEvent::Added(z1)),
+            ]),
+            stop: &stop,
+        };
+
+        let mut added = Vec::new();
+        watch_hotplug(&mut source, &stop, |device_type| added.push(device_type)).unwrap();
+
+        assert_eq!(added, vec![x2, z1]);
+    }
+
+    #[test]
+    fn waits_for_a_plugged_camera_to_mount() {
+        let z1 = test_devices::theta_z1();
+        let camera = |device_type| Camera::new("Test".to_string(), device_type, "1".to_string(), FileStrategy::Mount, Vec::new());
+        let scans = Cell::new(0);
+        // Nothing the first time, then another model, then the camera
+        let scan = || {
+            scans.set(scans.get() + 1);
+            match scans.get() {
+                1 => Vec::new(),
+                2 => vec![camera(test_devices::insta360_one_x2())],
+                _ => vec![camera(test_devices::insta360_one_x2()), camera(z1)],
+            }
+        };
+
+        let cameras = wait_for_cameras(z1, scan, Duration::ZERO, &AtomicBool::new(false));
+
+        assert_eq!(scans.get(), 3);
+        assert_eq!(cameras.len(), 1);
+        assert_eq!(cameras[0].device_type, z1);
+    }
+}
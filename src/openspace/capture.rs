@@ -0,0 +1,202 @@
+use crate::camera_fs::file_source::SourceFile;
+use crate::device_type::{DeviceType, VendorType};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+// VID_20240101_120000_00_001.insv -> kind, timestamp, lens, sequence
+static INSTA360_FILENAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^(VID|IMG|LRV)_(\d{8}_\d{6})_(\d{2})_(\d{3})\.(\w+)$").unwrap());
+
+// Lens indices in Insta360 filenames
+const INSTA360_FRONT_LENS: &str = "00";
+const INSTA360_BACK_LENS: &str = "10";
+
+/// A recording found on a camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    pub file: SourceFile,
+    pub content_type: &'static str,
+}
+
+/// Every file belonging to one shot: both lenses of an Insta360 video plus
+/// its LRV proxy, or a Theta JPEG with its DNG.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    // e.g. "20240101_120000_001" for Insta360, "R0010001" for Theta
+    pub id: String,
+    pub files: Vec<MediaFile>,
+    // Partner files that should exist but weren't found
+    pub missing: Vec<String>,
+}
+
+impl Capture {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Groups recordings into captures, in capture ID order. `single_lens` is set
+/// for cameras configured to record one lens only.
+pub fn group_captures(device_type: DeviceType, files: Vec<MediaFile>, single_lens: bool) -> Vec<Capture> {
+    let mut groups: BTreeMap<String, Vec<MediaFile>> = BTreeMap::new();
+    for media in files {
+        groups.entry(capture_id(device_type, media.file.name())).or_default().push(media);
+    }
+
+    groups
+        .into_iter()
+        .map(|(id, mut files)| {
+            files.sort_by(|a, b| a.file.path.cmp(&b.file.path));
+            let missing = match device_type.vendor() {
+                VendorType::Insta if device_type.splits_lens_videos() => insta360_missing_partners(&files, single_lens),
+                VendorType::Insta => Vec::new(),
+                VendorType::Theta => Vec::new(),
+            };
+            Capture { id, files, missing }
+        })
+        .collect()
+}
+
+fn capture_id(device_type: DeviceType, filename: &str) -> String {
+    if device_type.vendor() == VendorType::Insta {
+        if let Some(parts) = INSTA360_FILENAME.captures(filename) {
+            return format!("{}_{}", &parts[2], &parts[4]);
+        }
+    }
+
+    // Everything else groups by name without extension (R0010001.JPG + R0010001.DNG)
+    filename.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(filename).to_string()
+}
+
+/// A dual-lens video needs both its `_00_` and `_10_` halves. Single lens
+/// modes only write the `_00_` file, but nothing on the card tells that apart
+/// from a half that hasn't been written yet, so a lone one is only complete
+/// when the camera is configured as single lens. A still is a single `_00_`
+/// .insp, so only a lone `_10_` still is missing its partner. The LRV proxy is
+/// optional.
+fn insta360_missing_partners(files: &[MediaFile], single_lens: bool) -> Vec<String> {
+    let parsed: Vec<_> = files
+        .iter()
+        .filter_map(|m| INSTA360_FILENAME.captures(m.file.name()))
+        .collect();
+    let has = |kind: &str, lens: &str| parsed.iter().any(|p| p[1].eq_ignore_ascii_case(kind) && &p[3] == lens);
+
+    let mut missing = Vec::new();
+    for p in &parsed {
+        let kind = p[1].to_ascii_uppercase();
+        let partner_lens = match (kind.as_str(), &p[3]) {
+            ("VID", INSTA360_FRONT_LENS) if single_lens => continue,
+            ("VID", INSTA360_FRONT_LENS) => INSTA360_BACK_LENS,
+            ("VID" | "IMG", INSTA360_BACK_LENS) => INSTA360_FRONT_LENS,
+            _ => continue,
+        };
+
+        if !has(&kind, partner_lens) {
+            missing.push(format!("{}_{}_{}_{}.{}", &p[1], &p[2], partner_lens, &p[4], &p[5]));
+        }
+    }
+
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_type::test_devices;
+
+    fn media(path: &str) -> MediaFile {
+        MediaFile {
+            file: SourceFile {
+                path: path.to_string(),
+                size: 1,
+                modified: None,
+            },
+            content_type: "video/insv",
+        }
+    }
+
+    fn names(capture: &Capture) -> Vec<&str> {
+        capture.files.iter().map(|m| m.file.name()).collect()
+    }
+
+    #[test]
+    fn pairs_insta360_lenses_with_proxy() {
+        let captures = group_captures(
+            test_devices::insta360_one_x2(),
+            vec![
+                media("DCIM/Camera01/VID_20240101_120000_10_001.insv"),
+                media("DCIM/Camera01/LRV_20240101_120000_01_001.lrv"),
+                media("DCIM/Camera01/VID_20240101_120000_00_001.insv"),
+                media("DCIM/Camera01/IMG_20240101_130000_00_002.insp"),
+            ],
+            false,
+        );
+
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[0].id, "20240101_120000_001");
+        assert_eq!(
+            names(&captures[0]),
+            vec![
+                "LRV_20240101_120000_01_001.lrv",
+                "VID_20240101_120000_00_001.insv",
+                "VID_20240101_120000_10_001.insv",
+            ]
+        );
+        assert!(captures[0].is_complete());
+        assert_eq!(captures[1].id, "20240101_130000_002");
+        assert!(captures[1].is_complete());
+    }
+
+    #[test]
+    fn reports_missing_insta360_partner() {
+        let captures = group_captures(
+            test_devices::insta360_one_x2(),
+            vec![
+                media("DCIM/Camera01/VID_20240101_120000_00_001.insv"),
+                media("DCIM/Camera01/IMG_20240101_130000_10_002.insp"),
+            ],
+            false,
+        );
+
+        assert_eq!(captures[0].missing, vec!["VID_20240101_120000_10_001.insv"]);
+        assert_eq!(captures[1].missing, vec!["IMG_20240101_130000_00_002.insp"]);
+    }
+
+    #[test]
+    fn uploads_single_lens_insta360_videos() {
+        let files = vec![
+            media("DCIM/Camera01/VID_20240101_110000_00_001.insv"),
+            media("DCIM/Camera01/LRV_20240101_110000_01_001.lrv"),
+            media("DCIM/Camera01/VID_20240101_115500_10_002.insv"),
+        ];
+
+        // However old it is, the back lens half may still be on its way
+        let captures = group_captures(test_devices::insta360_one_x2(), files.clone(), false);
+        assert_eq!(captures[0].missing, vec!["VID_20240101_110000_10_001.insv"]);
+
+        let captures = group_captures(test_devices::insta360_one_x2(), files, true);
+        assert_eq!(captures.len(), 2);
+        assert_eq!(names(&captures[0]), vec!["LRV_20240101_110000_01_001.lrv", "VID_20240101_110000_00_001.insv"]);
+        assert!(captures[0].is_complete());
+        // A back lens half never exists on its own
+        assert_eq!(captures[1].missing, vec!["VID_20240101_115500_00_002.insv"]);
+    }
+
+    #[test]
+    fn groups_theta_files_by_name() {
+        let captures = group_captures(
+            test_devices::theta_z1(),
+            vec![
+                media("DCIM/100RICOH/R0010001.JPG"),
+                media("DCIM/100RICOH/R0010001.DNG"),
+                media("DCIM/100RICOH/R0010002.MP4"),
+            ],
+            false,
+        );
+
+        assert_eq!(captures.len(), 2);
+        assert_eq!(names(&captures[0]), vec!["R0010001.DNG", "R0010001.JPG"]);
+        assert!(captures.iter().all(Capture::is_complete));
+    }
+}
@@ -0,0 +1,76 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+/// Whole-file digests sent when creating an upload, as lowercase hex
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    pub md5: String,
+    pub sha256: Option<String>,
+}
+
+/**
+ * Hashes a file fed to it in order, one chunk at a time, so the file is never
+ * held in memory as a whole
+*/
+pub struct FileHasher {
+    md5: md5::Context,
+    sha256: Option<Sha256>,
+}
+
+impl FileHasher {
+    pub fn new(with_sha256: bool) -> Self {
+        Self {
+            md5: md5::Context::new(),
+            sha256: with_sha256.then(Sha256::new),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.md5.consume(chunk);
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(chunk);
+        }
+    }
+
+    pub fn finish(self) -> FileDigest {
+        FileDigest {
+            md5: format!("{:x}", self.md5.compute()),
+            sha256: self.sha256.map(|sha256| hex(&sha256.finalize())),
+        }
+    }
+}
+
+/// Value of a `Content-MD5` header (RFC 1864): base64 of the binary digest
+pub fn content_md5(chunk: &[u8]) -> String {
+    STANDARD.encode(md5::compute(chunk).0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_digest_matches_whole_file() {
+        let mut hasher = FileHasher::new(true);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let digest = hasher.finish();
+
+        assert_eq!(digest.md5, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(
+            digest.sha256.as_deref(),
+            Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+        );
+        assert_eq!(FileHasher::new(false).finish().sha256, None);
+    }
+
+    #[test]
+    fn content_md5_is_base64() {
+        assert_eq!(content_md5(b"hello world"), "XrY7u+Ae7tCTyyK7j1rNww==");
+    }
+}
@@ -0,0 +1,121 @@
+use crate::camera_fs::camera::Camera;
+use crate::openspace::upload_all_files::UploadEvent;
+use crate::storage::{get_storage_dir, unix_now};
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::pin::pin;
+
+// JSON lines, one per upload event, in ~/.openspace_sync
+const EVENT_LOG_FILE: &str = "upload_events.log";
+// The previous log, once the current one grows past EVENT_LOG_MAX_BYTES
+const EVENT_LOG_PREVIOUS_FILE: &str = "upload_events.log.1";
+const EVENT_LOG_MAX_BYTES: u64 = 5 * 1024 * 1024;
+
+/// Appends every event of an upload to the event log, with the time it arrived
+pub async fn write_event_log(events: impl Stream<Item = UploadEvent>) {
+    let dir = match get_storage_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Not logging upload events: {}", e);
+            return;
+        }
+    };
+
+    let mut events = pin!(events);
+    while let Some(event) = events.next().await {
+        let mut line = event_json(&event);
+        line["time"] = json!(unix_now());
+        // File writes block, so they run off the async workers
+        let dir = dir.clone();
+        let written = tokio::task::spawn_blocking(move || append_line(&dir, &line.to_string())).await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Failed to write {}: {}", EVENT_LOG_FILE, e),
+            Err(e) => eprintln!("Event log writer panicked: {}", e),
+        }
+    }
+}
+
+fn append_line(dir: &Path, line: &str) -> std::io::Result<()> {
+    let path = dir.join(EVENT_LOG_FILE);
+    if fs::metadata(&path).is_ok_and(|m| m.len() > EVENT_LOG_MAX_BYTES) {
+        fs::rename(&path, dir.join(EVENT_LOG_PREVIOUS_FILE))?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+pub fn camera_json(camera: &Camera) -> Value {
+    json!({
+        "name": camera.name,
+        "device_type": camera.device_type.to_string(),
+        "device_id": camera.device_id,
+        "connection": camera.fs_strategy.to_string(),
+        "volumes": camera.volumes.iter().map(|v| json!({
+            "name": v.name,
+            "mount_point": v.mount_point,
+            "capacity": v.capacity,
+            "free_space": v.free_space,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+pub fn event_json(event: &UploadEvent) -> Value {
+    match event {
+        UploadEvent::CameraFound(camera) => json!({ "event": "camera_found", "camera": camera_json(camera) }),
+        UploadEvent::FileStarted { device_id, filename, total_bytes } => json!({
+            "event": "file_started",
+            "device_id": device_id,
+            "filename": filename,
+            "total_bytes": total_bytes,
+        }),
+        UploadEvent::FileProgress {
+            device_id,
+            filename,
+            bytes_uploaded,
+            total_bytes,
+            parts_completed,
+            num_parts,
+        } => json!({
+            "event": "file_progress",
+            "device_id": device_id,
+            "filename": filename,
+            "bytes_uploaded": bytes_uploaded,
+            "total_bytes": total_bytes,
+            "parts_completed": parts_completed,
+            "num_parts": num_parts,
+        }),
+        UploadEvent::FileRetrying { device_id, filename, retries, reason } => json!({
+            "event": "file_retrying",
+            "device_id": device_id,
+            "filename": filename,
+            "retries": retries,
+            "reason": reason,
+        }),
+        UploadEvent::FileSkipped { device_id, filename } => {
+            json!({ "event": "file_skipped", "device_id": device_id, "filename": filename })
+        }
+        UploadEvent::FileCompleted { device_id, filename } => {
+            json!({ "event": "file_completed", "device_id": device_id, "filename": filename })
+        }
+        UploadEvent::FileFailed { device_id, filename, error } => json!({
+            "event": "file_failed",
+            "device_id": device_id,
+            "filename": filename,
+            "error": error,
+        }),
+        UploadEvent::FileCancelled { device_id, filename } => {
+            json!({ "event": "file_cancelled", "device_id": device_id, "filename": filename })
+        }
+        UploadEvent::CaptureIncomplete { device_id, capture_id, missing } => json!({
+            "event": "capture_incomplete",
+            "device_id": device_id,
+            "capture_id": capture_id,
+            "missing": missing,
+        }),
+    }
+}
@@ -1,2 +1,8 @@
+pub mod auto_sync;
+pub mod capture;
+pub mod digest;
+pub mod event_log;
 pub mod upload_all_files;
-pub mod model;
\ No newline at end of file
+pub mod upload_control;
+pub mod model;
+pub mod retry;
\ No newline at end of file
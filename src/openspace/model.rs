@@ -1,3 +1,4 @@
+use crate::openspace::digest::FileDigest;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,6 +13,15 @@ pub struct TicTacUploadRequest {
     pub size: i64,
     #[serde(rename = "numParts")]
     pub num_parts: i32,
+    // Files of the same shot (e.g. both lenses) share a capture ID
+    #[serde(rename = "captureId", skip_serializing_if = "Option::is_none")]
+    pub capture_id: Option<String>,
+    // Whole-file digests (lowercase hex), so the server can verify the upload
+    // and recognize a file it already has under another name
+    #[serde(rename = "md5", skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(rename = "sha256", skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
 }
 
 impl TicTacUploadRequest {
@@ -28,9 +38,24 @@ impl TicTacUploadRequest {
             content_type,
             size,
             num_parts,
+            capture_id: None,
+            md5: None,
+            sha256: None,
         }
     }
+
+    pub fn with_capture_id(mut self, capture_id: String) -> Self {
+        self.capture_id = Some(capture_id);
+        self
+    }
+
+    pub fn with_digest(mut self, digest: FileDigest) -> Self {
+        self.md5 = Some(digest.md5);
+        self.sha256 = digest.sha256;
+        self
+    }
 }
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetOrCreateUploadResponse {
     #[serde(rename = "uploadId")]
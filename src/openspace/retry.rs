@@ -0,0 +1,131 @@
+use futures::future::{self, Either};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::pin::pin;
+use std::time::Duration;
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+// Upper bound on how long we honor a server's Retry-After
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Exponential backoff for transient HTTP failures: 5xx, 408, 429, timeouts
+/// and dropped connections. Everything else (e.g. 4xx validation errors) is
+/// handed back to the caller on the first attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (0-based), with up to 25% jitter so
+    /// parallel parts that failed together don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        // RandomState is seeded randomly, which is plenty for jitter
+        let random = RandomState::new().build_hasher().finish();
+        delay + delay.mul_f64((random % 1000) as f64 / 4000.0)
+    }
+}
+
+/// Sends the request produced by `build`, rebuilding and resending it while the
+/// failure is transient and retries remain. `on_retry` is called with a short
+/// reason before each retry. The final response is returned as-is, so callers
+/// still check the status of non-retryable (or exhausted) responses. Once
+/// `interrupted` resolves, the wait for the next retry ends and the last
+/// failure is returned.
+pub async fn send_with_retry<F>(
+    policy: &RetryPolicy,
+    build: F,
+    on_retry: &(dyn Fn(&str) + Sync),
+    interrupted: impl Future<Output = ()>,
+) -> Result<Response, reqwest::Error>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let mut interrupted = pin!(interrupted);
+    let mut attempt = 0;
+
+    loop {
+        let result = build().send().await;
+
+        let (reason, retry_after) = match &result {
+            Ok(response) if is_retryable_status(response.status()) => {
+                (format!("HTTP {}", response.status()), retry_after(response))
+            }
+            Err(e) if is_transient_error(e) => (e.to_string(), None),
+            _ => return result,
+        };
+
+        if attempt >= policy.max_retries {
+            return result;
+        }
+
+        let delay = retry_after.unwrap_or_else(|| policy.backoff(attempt));
+        attempt += 1;
+
+        info!("{}, retry {}/{} in {:?}", reason, attempt, policy.max_retries, delay);
+        on_retry(&reason);
+
+        if let Either::Right(_) = future::select(pin!(tokio::time::sleep(delay)), interrupted.as_mut()).await {
+            info!("Not retrying, interrupted");
+            return result;
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// Only the delay-seconds form; an HTTP-date falls back to our own backoff
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds: u64 = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+fn is_transient_error(error: &reqwest::Error) -> bool {
+    if error.is_timeout() || error.is_connect() {
+        return true;
+    }
+
+    // Connection resets surface as a request error with an io::Error underneath
+    let mut source = error.source();
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::TimedOut
+            );
+        }
+        source = e.source();
+    }
+
+    false
+}
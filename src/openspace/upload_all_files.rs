@@ -1,184 +1,561 @@
-use crate::camera_fs::camera_finder::scan_for_camera_fs;
-use crate::api::http_client;
-use crate::storage::{add_skipped_file, is_file_skipped, SkippedFile};
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
-use walkdir::WalkDir;
+use crate::camera_fs::camera::Camera;
+use crate::camera_fs::camera_finder::{camera_from_folder, scan_for_camera_fs};
+use crate::camera_fs::file_source::{open_file_sources, FileSource, SourceFile};
+use crate::camera_fs::hotplug::pause_polling;
+use crate::openspace::capture::{group_captures, MediaFile};
+use crate::openspace::digest::{content_md5, FileDigest, FileHasher};
+use crate::openspace::upload_control::UploadControl;
+use crate::api::{http_client, send_authorized, ApiClient};
+use crate::config::settings;
+use crate::storage::{
+    find_pending_upload, find_uploaded_file, forget_file, is_file_synced, lock_sync_run, mark_file_skipped,
+    mark_range_completed, mark_upload_completed, save_pending_upload, FileRecord,
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, Stream, StreamExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use crate::openspace::model::{TicTacUploadRequest, GetOrCreateUploadResponse};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use crate::openspace::retry::{send_with_retry, RetryPolicy};
+use bytes::Bytes;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::sync::mpsc;
 
-const API_BASE_URL: &str = "http://localhost:8080/api";
 const CHUNK_SIZE: i64 = 8 * 1024 * 1024; // 8MB chunks
+// Files are read this much at a time, checking in between whether the file
+// was stopped
+const READ_BLOCK_SIZE: i64 = 1024 * 1024;
+const DEFAULT_PARALLEL_FILES: usize = 2;
+const DEFAULT_PARALLEL_PARTS: usize = 4;
 
-#[derive(Debug)]
 struct FileToUpload {
-    path: PathBuf,
-    size: i64,
+    source: Arc<dyn FileSource>,
+    file: SourceFile,
+    content_type: &'static str,
+    device_id: String,
+    capture_id: String,
 }
 
 #[derive(Debug)]
 enum UploadResult {
     Completed,
     Skipped,
+    // Stopped through the UploadControl
+    Cancelled,
+}
+
+/// What the server reports for an upload we started on an earlier run
+#[derive(Debug)]
+enum ServerUploadState {
+    // Byte ranges (inclusive) the server already has
+    Partial(Vec<(i64, i64)>),
+    Complete,
+    // Upload expired or was never created
+    Unknown,
+}
+
+/// HTTP plumbing shared by every request made for one file
+struct RequestContext<'a> {
+    client: ApiClient,
+    retry: RetryPolicy,
+    on_retry: &'a (dyn Fn(&str) + Sync),
+    // Resolves once the file is stopped, which ends any wait for a retry
+    stopped: &'a (dyn Fn() -> BoxFuture<'a, ()> + Sync),
+}
+
+impl RequestContext<'_> {
+    /// Sends with retries for transient failures, and a token refresh plus one
+    /// replay when the server rejects our access token
+    async fn send<F>(&self, build: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        send_authorized(|| send_with_retry(&self.retry, &build, self.on_retry, (self.stopped)())).await
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum UploadEvent {
-    CameraFound(String),
-    FileStarted { filename: String, total_bytes: i64 },
-    FileProgress { filename: String, bytes_uploaded: i64, total_bytes: i64 },
-    FileSkipped { filename: String },
-    FileCompleted { filename: String },
-    FileFailed { filename: String, error: String },
+    CameraFound(Camera),
+    FileStarted { device_id: String, filename: String, total_bytes: i64 },
+    FileProgress {
+        device_id: String,
+        filename: String,
+        bytes_uploaded: i64,
+        total_bytes: i64,
+        parts_completed: i32,
+        num_parts: i32,
+    },
+    // A request for this file failed transiently and is being retried;
+    // `retries` counts every retry for the file so far
+    FileRetrying { device_id: String, filename: String, retries: u32, reason: String },
+    FileSkipped { device_id: String, filename: String },
+    FileCompleted { device_id: String, filename: String },
+    FileFailed { device_id: String, filename: String, error: String },
+    // Cancelled or skipped through the UploadControl; an upload already
+    // created on the server is resumed by the next run
+    FileCancelled { device_id: String, filename: String },
+    // Some files of a capture are missing, so none of it is uploaded
+    CaptureIncomplete { device_id: String, capture_id: String, missing: Vec<String> },
 }
 
-pub fn upload_all_files(progress_tx: Option<Sender<UploadEvent>>) -> Result<(), Box<dyn std::error::Error>> {
-    let camera_info = match scan_for_camera_fs() {
-        Some(info) => info,
-        None => {
-            println!("No camera volume found");
-            return Ok(()); // exit the function cleanly
+/**
+ * Where an upload publishes its events, for any number of subscribers such as
+ * the UI, a log file and the CLI. Each gets every event sent after it
+ * subscribed, in order and however far behind it falls, since counts like
+ * skipped and failed files are taken from them. Subscriptions end once every
+ * clone of the `UploadEvents` is dropped, so hand the last one to the upload.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct UploadEvents {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<UploadEvent>>>>,
+}
+
+impl UploadEvents {
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<UploadEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers().push(tx);
+        rx
+    }
+
+    /// A subscription as a stream
+    pub fn stream(&self) -> impl Stream<Item = UploadEvent> + Send + 'static {
+        stream::unfold(self.subscribe(), |mut rx| async move { rx.recv().await.map(|event| (event, rx)) })
+    }
+
+    fn send(&self, event: UploadEvent) {
+        // Subscribers that went away are dropped; nobody listening is fine
+        self.subscribers().retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    fn subscribers(&self) -> std::sync::MutexGuard<'_, Vec<mpsc::UnboundedSender<UploadEvent>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// How many uploads run at the same time. Memory use is bounded by
+/// `files * parts_per_file * CHUNK_SIZE`.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadConcurrency {
+    pub files: usize,
+    pub parts_per_file: usize,
+}
+
+impl Default for UploadConcurrency {
+    fn default() -> Self {
+        Self {
+            files: DEFAULT_PARALLEL_FILES,
+            parts_per_file: DEFAULT_PARALLEL_PARTS,
         }
-    };
+    }
+}
 
-    println!("Found camera volume: {:?}", camera_info.mount_point);
-    println!("Device ID: {}", camera_info.device_id);
+/// How files the local sync state already knows to be on the server are handled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncCheck {
+    // Skip them without contacting the server
+    #[default]
+    Local,
+    // Ask the server again, and upload whatever it no longer has
+    Server,
+}
 
-    // Notify UI that camera was found
-    if let Some(ref tx) = progress_tx {
-        let _ = tx.send(UploadEvent::CameraFound(camera_info.device_id.clone()));
+/// Syncs every detected camera, or only those whose device ID is in
+/// `device_ids` when a selection is given. Progress goes to `events`, whose
+/// subscriptions end once this returns and the caller has dropped its copy.
+pub async fn upload_all_files(
+    device_ids: Option<&[String]>,
+    concurrency: UploadConcurrency,
+    check: SyncCheck,
+    events: UploadEvents,
+    control: &UploadControl,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Camera polling would open MTP sessions next to the ones uploads read from
+    let _polling = pause_polling();
+    let cameras: Vec<Camera> = tokio::task::spawn_blocking(scan_for_camera_fs)
+        .await?
+        .into_iter()
+        .filter(|c| device_ids.is_none_or(|ids| ids.contains(&c.device_id)))
+        .collect();
+
+    if cameras.is_empty() {
+        info!("No camera found");
+        return Ok(()); // exit the function cleanly
     }
 
-    // Step 1: Find all .insv files (filtered by skipped files cache)
-    let insv_files: Vec<PathBuf> = collect_insv_files(camera_info.mount_point, &camera_info.device_id, progress_tx.as_ref())?;
-    println!("Found {} .insv files to upload", insv_files.len());
+    upload_cameras(cameras, concurrency, check, events, control).await
+}
+
+/// Syncs the recordings in a folder instead of a connected camera, see
+/// `camera_from_folder` for how the device is determined
+pub async fn upload_folder(
+    folder: &Path,
+    device_id: Option<&str>,
+    concurrency: UploadConcurrency,
+    check: SyncCheck,
+    events: UploadEvents,
+    control: &UploadControl,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let folder = folder.to_path_buf();
+    let device_id = device_id.map(str::to_string);
+    let camera = tokio::task::spawn_blocking(move || {
+        camera_from_folder(&folder, device_id.as_deref()).map_err(|e| e.to_string())
+    })
+    .await??;
 
-    if insv_files.is_empty() {
-        println!("No files to upload");
+    upload_cameras(vec![camera], concurrency, check, events, control).await
+}
+
+async fn upload_cameras(
+    cameras: Vec<Camera>,
+    concurrency: UploadConcurrency,
+    check: SyncCheck,
+    events: UploadEvents,
+    control: &UploadControl,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Another instance syncing the same cameras would upload the same files
+    let _sync_lock = lock_sync_run()?;
+
+    // Notify UI about every camera we are about to sync
+    for camera in &cameras {
+        events.send(UploadEvent::CameraFound(camera.clone()));
+    }
+
+    // Step 1: Listing files reads from the cameras, so it runs off the async workers
+    let files = tokio::task::spawn_blocking({
+        let events = events.clone();
+        move || find_files_to_upload(&cameras, check, &events)
+    })
+    .await?;
+
+    if files.is_empty() {
+        info!("No files to upload");
         return Ok(());
     }
 
-    // Create a Tokio runtime for async operations
-    let runtime = tokio::runtime::Runtime::new()?;
+    // Step 2: Upload up to `concurrency.files` files at once
+    stream::iter(files)
+        .map(|file| process_file(file, concurrency.parts_per_file, check, &events, control))
+        .buffer_unordered(concurrency.files.max(1))
+        .collect::<Vec<()>>()
+        .await;
 
-    // Step 2: Upload each file
-    for file in insv_files {
-        let filename = file.file_name().unwrap().to_str().unwrap().to_string();
-        let file_size = file.metadata()?.len() as i64;
+    info!("Upload process completed");
+    Ok(())
+}
 
-        let request = TicTacUploadRequest::new(
-            camera_info.device_id.clone(),
-            filename.clone(),
-            "video/insv".to_string(),
-            file_size,
-            1,
-        );
+/// The recordings on every camera, grouped into captures and filtered by the
+/// local sync state unless verifying with the server
+fn find_files_to_upload(cameras: &[Camera], check: SyncCheck, events: &UploadEvents) -> Vec<FileToUpload> {
+    let mut files = Vec::new();
+    for camera in cameras {
+        info!("Found camera: {} over {}", camera.name, camera.fs_strategy);
+        info!("Device ID: {}", camera.device_id);
 
-        // Notify UI that file upload is starting
-        if let Some(ref tx) = progress_tx {
-            let _ = tx.send(UploadEvent::FileStarted {
-                filename: filename.clone(),
-                total_bytes: file_size,
-            });
-        }
+        let sources = match open_file_sources(camera) {
+            Ok(sources) => sources,
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", camera.name, e);
+                continue;
+            }
+        };
 
-        match runtime.block_on(upload_file(&file, request, progress_tx.clone(), &camera_info.device_id)) {
-            Ok(UploadResult::Completed) => {
-                println!("Successfully uploaded: {:?}", filename);
-                if let Some(ref tx) = progress_tx {
-                    let _ = tx.send(UploadEvent::FileCompleted { filename });
+        for source in sources {
+            let media_files = match collect_media_files(source.as_ref(), camera) {
+                Ok(media_files) => media_files,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", camera.name, e);
+                    continue;
                 }
-            }
-            Ok(UploadResult::Skipped) => {
-                println!("File already exists on server, skipping: {:?}", filename);
-                // Add to skipped files cache
-                let skipped = SkippedFile::new(filename.clone(), file_size, camera_info.device_id.clone());
-                if let Err(e) = add_skipped_file(skipped) {
-                    eprintln!("Failed to cache skipped file: {}", e);
+            };
+
+            let single_lens = settings().upload.single_lens_devices.contains(&camera.device_id);
+            let captures = group_captures(camera.device_type, media_files, single_lens);
+            info!("Found {} captures", captures.len());
+
+            for capture in captures {
+                if !capture.is_complete() {
+                    eprintln!("Capture {} is missing {}, not uploading it", capture.id, capture.missing.join(", "));
+                    events.send(UploadEvent::CaptureIncomplete {
+                        device_id: camera.device_id.clone(),
+                        capture_id: capture.id.clone(),
+                        missing: capture.missing.clone(),
+                    });
+                    continue;
                 }
-                if let Some(ref tx) = progress_tx {
-                    let _ = tx.send(UploadEvent::FileSkipped { filename });
+
+                for media in capture.files {
+                    if check == SyncCheck::Local && is_cached_skip(&media.file, &camera.device_id, events) {
+                        continue;
+                    }
+                    files.push(FileToUpload {
+                        source: source.clone(),
+                        file: media.file,
+                        content_type: media.content_type,
+                        device_id: camera.device_id.clone(),
+                        capture_id: capture.id.clone(),
+                    });
                 }
             }
-            Err(e) => {
-                eprintln!("Failed to upload {:?}: {}", filename, e);
-                if let Some(ref tx) = progress_tx {
-                    let _ = tx.send(UploadEvent::FileFailed {
+        }
+    }
+
+    files
+}
+
+/// Uploads one file and reports its outcome. Every event for a file is sent
+/// from inside this future, so they arrive in order even with other files in flight.
+async fn process_file(
+    file: FileToUpload,
+    parallel_parts: usize,
+    check: SyncCheck,
+    events: &UploadEvents,
+    control: &UploadControl,
+) {
+    let filename = file.file.name().to_string();
+    let size = file.file.size as i64;
+    let device_id = file.device_id.clone();
+
+    // A paused run doesn't start new files either
+    if !control.proceed(&device_id, &filename).await {
+        info!("Not uploading {:?}, stopped", filename);
+        events.send(UploadEvent::FileCancelled { device_id, filename });
+        return;
+    }
+
+    // Files the server told us it already had are re-checked by creating the
+    // upload again below; our own uploads are looked up by their upload ID
+    if check == SyncCheck::Server {
+        let (id, source_file) = (device_id.clone(), file.file.clone());
+        if let Some(uploaded) = read_state(move || find_uploaded_file(&id, &source_file)).await {
+            // As a String, since a boxed error isn't Send and the arms below await
+            match verify_upload(&uploaded).await.map_err(|e| e.to_string()) {
+                Ok(true) => {
+                    info!("Verified on server: {:?}", filename);
+                    events.send(UploadEvent::FileSkipped { device_id, filename });
+                    return;
+                }
+                Ok(false) => {
+                    info!("{:?} is no longer complete on the server, uploading again", filename);
+                    let (id, source_file) = (device_id.clone(), file.file.clone());
+                    if let Err(e) = write_state(move || forget_file(&id, &source_file)).await {
+                        eprintln!("Failed to update sync state: {}", e);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to verify {:?}: {}", filename, e);
+                    events.send(UploadEvent::FileFailed {
+                        device_id,
                         filename,
                         error: e.to_string(),
                     });
+                    return;
                 }
             }
         }
     }
 
-    println!("Upload process completed");
-    Ok(())
-}
+    let request = TicTacUploadRequest::new(
+        device_id.clone(),
+        filename.clone(),
+        file.content_type.to_string(),
+        size,
+        num_parts(size),
+    )
+    .with_capture_id(file.capture_id.clone());
 
-fn collect_insv_files(
-    volume: PathBuf,
-    device_id: &str,
-    progress_tx: Option<&Sender<UploadEvent>>,
-) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
-    let mut insv_files = Vec::new();
-
-    for entry in WalkDir::new(volume) {
-        match entry {
-            Ok(entry) => {
-                if entry.file_type().is_file() {
-                    if let Some(ext) = entry.path().extension() {
-                        if ext.eq_ignore_ascii_case("insv") {
-                            let path = entry.path().to_path_buf();
-
-                            // Check if this file is in the skipped cache
-                            if let Some(filename) = path.file_name().and_then(|f| f.to_str()) {
-                                if let Ok(metadata) = entry.metadata() {
-                                    let size = metadata.len() as i64;
-
-                                    // Skip if already in cache
-                                    if is_file_skipped(filename, size, device_id) {
-                                        println!("Skipping cached file: {}", filename);
-
-                                        // Send event for cached skipped file
-                                        if let Some(tx) = progress_tx {
-                                            let _ = tx.send(UploadEvent::FileSkipped {
-                                                filename: filename.to_string(),
-                                            });
-                                        }
-                                        continue;
-                                    }
-                                }
-                            }
-
-                            insv_files.push(path);
-                        }
-                    }
-                }
+    // Notify UI that file upload is starting
+    events.send(UploadEvent::FileStarted {
+        device_id: device_id.clone(),
+        filename: filename.clone(),
+        total_bytes: size,
+    });
+
+    let result = upload_file(
+        &file.source,
+        &file.file,
+        request,
+        events,
+        &device_id,
+        parallel_parts,
+        settings().upload.retry_policy(),
+        control,
+    )
+    .await;
+
+    // As a String, since a boxed error isn't Send and the arms below await
+    match result.map_err(|e| e.to_string()) {
+        Ok(UploadResult::Completed) => {
+            info!("Successfully uploaded: {:?}", filename);
+            events.send(UploadEvent::FileCompleted { device_id, filename });
+        }
+        Ok(UploadResult::Skipped) => {
+            info!("File already exists on server, skipping: {:?}", filename);
+            // Remember it, so the next run doesn't have to ask the server
+            let (id, source_file) = (device_id.clone(), file.file.clone());
+            if let Err(e) = write_state(move || mark_file_skipped(&id, &source_file)).await {
+                eprintln!("Failed to cache skipped file: {}", e);
             }
-            Err(e) => eprintln!("Error reading directory entry: {}", e),
+            events.send(UploadEvent::FileSkipped { device_id, filename });
+        }
+        Ok(UploadResult::Cancelled) => {
+            info!("Stopped uploading {:?}", filename);
+            events.send(UploadEvent::FileCancelled { device_id, filename });
+        }
+        // A request cut short by the stop rather than a failure of its own
+        Err(_) if control.is_stopped(&device_id, &filename) => {
+            info!("Stopped uploading {:?}", filename);
+            events.send(UploadEvent::FileCancelled { device_id, filename });
+        }
+        Err(e) => {
+            eprintln!("Failed to upload {:?}: {}", filename, e);
+            events.send(UploadEvent::FileFailed {
+                device_id,
+                filename,
+                error: e.to_string(),
+            });
         }
     }
+}
+
+/// Every recording on a camera, across all of its volumes
+pub fn list_camera_files(camera: &Camera) -> Result<Vec<MediaFile>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for source in open_file_sources(camera)? {
+        files.extend(collect_media_files(source.as_ref(), camera)?);
+    }
 
-    Ok(insv_files)
+    Ok(files)
 }
 
-async fn upload_file(
-    file: &PathBuf,
-    req: TicTacUploadRequest,
-    progress_tx: Option<Sender<UploadEvent>>,
-    _device_id: &str,
-) -> Result<UploadResult, Box<dyn std::error::Error>> {
-    // Step 1: Create the upload on the backend
-    let client = http_client();
-    let create_url = format!("{}/tictac/uploads", API_BASE_URL);
-
-    let response = client
-        .post(&create_url)
-        .json(&req)
-        .send()
-        .await?;
+/// Picks the files the camera syncs (see `Camera::content_type_for`)
+fn collect_media_files(source: &dyn FileSource, camera: &Camera) -> Result<Vec<MediaFile>, Box<dyn std::error::Error>> {
+    let media_files = source
+        .list_files()?
+        .into_iter()
+        .filter_map(|file| {
+            let content_type = camera.content_type_for(&file.path)?;
+            Some(MediaFile { file, content_type })
+        })
+        .collect();
+
+    Ok(media_files)
+}
+
+/// Whether the local sync state knows the file to be on the server
+fn is_cached_skip(file: &SourceFile, device_id: &str, events: &UploadEvents) -> bool {
+    if !is_file_synced(device_id, file) {
+        return false;
+    }
+    info!("Skipping cached file: {}", file.name());
+
+    // Send event for cached skipped file
+    events.send(UploadEvent::FileSkipped {
+        device_id: device_id.to_string(),
+        filename: file.name().to_string(),
+    });
+
+    true
+}
+
+/// Runs a sync state lookup on the blocking pool, off the async workers
+async fn read_state<T: Send + 'static>(read: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(read).await.expect("sync state lookup panicked")
+}
+
+/// Runs a sync state change on the blocking pool, off the async workers
+async fn write_state(
+    write: impl FnOnce() -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // As a String, since a boxed error isn't Send
+    let result = tokio::task::spawn_blocking(move || write().map_err(|e| e.to_string())).await?;
+    Ok(result?)
+}
+
+fn api_base_url() -> &'static str {
+    &settings().api.api_base_url
+}
+
+/// Number of CHUNK_SIZE parts needed for a file; an empty file is still one part.
+fn num_parts(file_size: i64) -> i32 {
+    ((file_size + CHUNK_SIZE - 1) / CHUNK_SIZE).max(1) as i32
+}
+
+/// Inclusive byte range of a part, as used in the Content-Range header
+fn part_range(part: i32, file_size: i64) -> (i64, i64) {
+    let start = part as i64 * CHUNK_SIZE;
+    let end = (start + CHUNK_SIZE).min(file_size) - 1;
+    (start, end)
+}
+
+/// Reads a single part from the camera, so at most CHUNK_SIZE bytes of the
+/// file are held in memory at any time.
+fn read_part(source: &dyn FileSource, file: &SourceFile, start: i64, end: i64) -> std::io::Result<Vec<u8>> {
+    let chunk_len = (end - start + 1).max(0) as u64;
+    let buffer = source.read_range(file, start as u64, chunk_len)?;
+
+    if buffer.len() as u64 != chunk_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("file shrank while reading bytes {}-{}", start, end),
+        ));
+    }
+
+    Ok(buffer)
+}
+
+/// Reads a part a block at a time. `None` when `stopped` says so before the
+/// part is read through.
+fn read_part_in_blocks(
+    source: &dyn FileSource,
+    file: &SourceFile,
+    start: i64,
+    end: i64,
+    stopped: &impl Fn() -> bool,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::with_capacity((end - start + 1).max(0) as usize);
+
+    for block_start in (start..=end).step_by(READ_BLOCK_SIZE as usize) {
+        if stopped() {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&read_part(source, file, block_start, (block_start + READ_BLOCK_SIZE - 1).min(end))?);
+    }
+
+    Ok(Some(buffer))
+}
+
+/// Reads the whole file once, a part at a time, to digest it. This has to
+/// happen before the upload is created since the server dedupes on the digest.
+/// `None` when `stopped` says so before the file is read through.
+fn hash_file(
+    source: &dyn FileSource,
+    file: &SourceFile,
+    with_sha256: bool,
+    stopped: impl Fn() -> bool,
+) -> std::io::Result<Option<FileDigest>> {
+    let file_size = file.size as i64;
+    let mut hasher = FileHasher::new(with_sha256);
+
+    for part in 0..num_parts(file_size) {
+        let (start, end) = part_range(part, file_size);
+        let Some(buffer) = read_part_in_blocks(source, file, start, end, &stopped)? else {
+            return Ok(None);
+        };
+        hasher.update(&buffer);
+    }
+
+    Ok(Some(hasher.finish()))
+}
+
+async fn create_upload(
+    ctx: &RequestContext<'_>,
+    req: &TicTacUploadRequest,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let create_url = format!("{}/tictac/uploads", api_base_url());
+
+    let response = ctx.send(|| ctx.client.post(&create_url).json(req)).await?;
 
     if !response.status().is_success() {
         return Err(format!("Failed to create upload: {}", response.status()).into());
@@ -186,64 +563,514 @@ async fn upload_file(
 
     let create_response: GetOrCreateUploadResponse = response.json().await?;
 
-    // Step 2: Check if we need to upload (uploadId will be None if file already exists)
-    let upload_id = match create_response.upload_id {
-        Some(id) => id,
-        None => {
-            println!("File already exists on server, skipping upload");
-            return Ok(UploadResult::Skipped);
+    Ok(create_response.upload_id)
+}
+
+/// Asks the server which bytes of an upload it has received, by sending an empty
+/// PUT with `Content-Range: bytes */<size>`. An unfinished upload answers with
+/// 308 and a `Range: bytes=0-N` header (absent when nothing arrived yet).
+async fn query_upload_state(
+    ctx: &RequestContext<'_>,
+    upload_id: &str,
+    file_size: i64,
+) -> Result<ServerUploadState, Box<dyn std::error::Error>> {
+    let upload_url = format!("{}/tictac/uploads/{}", api_base_url(), upload_id);
+    let content_range = format!("bytes */{}", file_size);
+
+    let response = ctx
+        .send(|| {
+            ctx.client
+                .put(&upload_url)
+                .header("Content-Range", &content_range)
+                .body(Vec::new())
+        })
+        .await?;
+
+    match response.status() {
+        StatusCode::PERMANENT_REDIRECT => {
+            let ranges = response
+                .headers()
+                .get("Range")
+                .and_then(|v| v.to_str().ok())
+                .map(parse_range_header)
+                .unwrap_or_default();
+            Ok(ServerUploadState::Partial(ranges))
         }
+        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(ServerUploadState::Unknown),
+        status if status.is_success() => Ok(ServerUploadState::Complete),
+        status => Err(format!("Failed to query upload {}: {}", upload_id, status).into()),
+    }
+}
+
+// "bytes=0-8388607,16777216-25165823" -> [(0, 8388607), (16777216, 25165823)]
+// Ranges that don't parse, are open-ended or run backwards are left out, so
+// those bytes are sent again rather than assumed to be on the server.
+fn parse_range_header(value: &str) -> Vec<(i64, i64)> {
+    value
+        .trim()
+        .trim_start_matches("bytes=")
+        .split(',')
+        .filter_map(|r| {
+            let (start, end) = r.trim().split_once('-')?;
+            let (start, end): (i64, i64) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+            (0 <= start && start <= end).then_some((start, end))
+        })
+        .collect()
+}
+
+/// Digests the whole file off the async workers. `None` when the file is
+/// stopped while reading.
+async fn digest_file(
+    source: &Arc<dyn FileSource>,
+    file: &SourceFile,
+    control: &UploadControl,
+    device_id: &str,
+) -> Result<Option<FileDigest>, Box<dyn std::error::Error>> {
+    info!("Hashing {:?}", file.name());
+
+    let (source, file) = (source.clone(), file.clone());
+    let (control, device_id) = (control.clone(), device_id.to_string());
+    let with_sha256 = settings().upload.sha256;
+    let digest = tokio::task::spawn_blocking(move || {
+        let stopped = || control.is_stopped(&device_id, file.name());
+        hash_file(source.as_ref(), &file, with_sha256, stopped)
+    })
+    .await??;
+
+    Ok(digest)
+}
+
+/// Reads only this part from the camera, off the async workers. `None` when
+/// the file is stopped while reading.
+async fn read_part_for_upload(
+    source: &Arc<dyn FileSource>,
+    file: &SourceFile,
+    part: i32,
+    file_size: i64,
+    control: &UploadControl,
+    device_id: &str,
+) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+    let (start, end) = part_range(part, file_size);
+    let (source, file) = (source.clone(), file.clone());
+    let (control, device_id) = (control.clone(), device_id.to_string());
+    let buffer = tokio::task::spawn_blocking(move || {
+        let stopped = || control.is_stopped(&device_id, file.name());
+        read_part_in_blocks(source.as_ref(), &file, start, end, &stopped)
+    })
+    .await??;
+
+    // Retries resend the same chunk; Bytes clones share it instead of copying 8MB
+    Ok(buffer.map(Bytes::from))
+}
+
+/// Sends a single part and returns the byte range the server acknowledged
+async fn upload_part(
+    ctx: &RequestContext<'_>,
+    upload_url: &str,
+    buffer: Bytes,
+    part: i32,
+    num_parts: i32,
+    file_size: i64,
+) -> Result<(i64, i64), Box<dyn std::error::Error>> {
+    let (start, end) = part_range(part, file_size);
+
+    // Upload chunk with Content-Range header, and its MD5 so the server can reject a corrupted chunk
+    let content_range = format!("bytes {}-{}/{}", start, end, file_size);
+    let md5 = content_md5(&buffer);
+
+    let response = ctx
+        .send(|| {
+            ctx.client
+                .put(upload_url)
+                .header("Content-Range", &content_range)
+                .header("Content-Type", "application/octet-stream")
+                .header("Content-MD5", &md5)
+                .body(buffer.clone())
+        })
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("Failed to upload chunk {}: {}", part, response.status()).into());
+    }
+
+    info!("Uploaded chunk {}/{} (bytes {}-{})", part + 1, num_parts, start, end);
+
+    Ok((start, end))
+}
+
+/// Whether an upload we finished earlier is still complete on the server
+async fn verify_upload(record: &FileRecord) -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(upload_id) = &record.upload_id else {
+        return Ok(false);
     };
 
-    // Step 3: Upload the file in chunks
-    let mut file_handle = File::open(file)?;
+    let on_retry = |_: &str| {};
+    let stopped = || future::pending().boxed();
+    let ctx = RequestContext {
+        client: http_client(),
+        retry: settings().upload.retry_policy(),
+        on_retry: &on_retry,
+        stopped: &stopped,
+    };
+
+    let state = query_upload_state(&ctx, upload_id, record.size).await?;
+    Ok(matches!(state, ServerUploadState::Complete))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload_file(
+    source: &Arc<dyn FileSource>,
+    file: &SourceFile,
+    req: TicTacUploadRequest,
+    events: &UploadEvents,
+    device_id: &str,
+    parallel_parts: usize,
+    retry: RetryPolicy,
+    control: &UploadControl,
+) -> Result<UploadResult, Box<dyn std::error::Error>> {
     let file_size = req.size;
     let filename = req.device_filename.clone();
     let num_parts = req.num_parts.max(1); // Ensure at least 1 part
-    let chunk_size = if num_parts == 1 {
-        file_size
-    } else {
-        (file_size as f64 / num_parts as f64).ceil() as i64
+
+    // Every retry for this file, across the create call and all parts
+    let retries = AtomicU32::new(0);
+    let on_retry = |reason: &str| {
+        let retries = retries.fetch_add(1, Ordering::Relaxed) + 1;
+        events.send(UploadEvent::FileRetrying {
+            device_id: device_id.to_string(),
+            filename: filename.clone(),
+            retries,
+            reason: reason.to_string(),
+        });
+    };
+    let stopped = || control.stopped(device_id, &filename).boxed();
+    let ctx = RequestContext {
+        client: http_client(),
+        retry,
+        on_retry: &on_retry,
+        stopped: &stopped,
     };
 
-    for part in 0..num_parts {
-        let start = part as i64 * chunk_size;
-        let end = ((part + 1) as i64 * chunk_size).min(file_size) - 1;
-        let chunk_len = (end - start + 1) as usize;
-
-        // Read chunk from file
-        file_handle.seek(SeekFrom::Start(start as u64))?;
-        let mut buffer = vec![0u8; chunk_len];
-        file_handle.read_exact(&mut buffer)?;
-
-        // Upload chunk with Content-Range header
-        let upload_url = format!("{}/tictac/uploads/{}", API_BASE_URL, upload_id);
-        let content_range = format!("bytes {}-{}/{}", start, end, file_size);
-
-        let response = client
-            .put(&upload_url)
-            .header("Content-Range", content_range)
-            .header("Content-Type", "application/octet-stream")
-            .body(buffer)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            return Err(format!("Failed to upload chunk {}: {}", part, response.status()).into());
+    // Step 1: Pick up an upload left unfinished by an earlier run
+    let mut resumed = None;
+    let (id, source_file) = (device_id.to_string(), file.clone());
+    if let Some(mut previous) = read_state(move || find_pending_upload(&id, &source_file)).await {
+        let upload_id = previous.upload_id.clone().ok_or("Pending upload has no upload ID")?;
+        let server_state = query_upload_state(&ctx, &upload_id, file_size).await?;
+        match server_state {
+            ServerUploadState::Partial(ranges) => {
+                info!("Resuming upload {} for {}", upload_id, filename);
+                previous.completed_parts = Vec::new();
+                for (start, end) in ranges {
+                    previous.mark_range_completed(start, end);
+                }
+                resumed = Some((previous, upload_id));
+            }
+            ServerUploadState::Complete => {
+                info!("Upload {} already completed on server", upload_id);
+                let (id, source_file) = (device_id.to_string(), file.clone());
+                let md5 = previous.md5.clone();
+                write_state(move || mark_upload_completed(&id, &source_file, md5)).await?;
+                return Ok(UploadResult::Completed);
+            }
+            ServerUploadState::Unknown => {
+                info!("Upload {} no longer exists on server, starting over", upload_id);
+                let (id, source_file) = (device_id.to_string(), file.clone());
+                write_state(move || forget_file(&id, &source_file)).await?;
+            }
         }
+    }
 
-        println!("Uploaded chunk {}/{} (bytes {}-{})", part + 1, num_parts, start, end);
+    // Step 2: Otherwise create the upload on the backend (uploadId will be None if file already exists)
+    let (pending, upload_id) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let Some(digest) = digest_file(source, file, control, device_id).await? else {
+                return Ok(UploadResult::Cancelled);
+            };
+            let md5 = digest.md5.clone();
+            match create_upload(&ctx, &req.with_digest(digest)).await? {
+                Some(upload_id) => (FileRecord::uploading(device_id, file, upload_id.clone(), Some(md5)), upload_id),
+                None => {
+                    info!("File already exists on server, skipping upload");
+                    return Ok(UploadResult::Skipped);
+                }
+            }
+        }
+    };
 
-        // Send progress update
-        if let Some(ref tx) = progress_tx {
-            let bytes_uploaded = end + 1;
-            let _ = tx.send(UploadEvent::FileProgress {
-                filename: filename.clone(),
-                bytes_uploaded,
-                total_bytes: file_size,
-            });
+    let record = pending.clone();
+    if let Err(e) = write_state(move || save_pending_upload(&record)).await {
+        eprintln!("Failed to save upload state: {}", e);
+    }
+
+    // Step 3: Upload the parts the server doesn't have yet, `parallel_parts` at a time
+    let upload_url = format!("{}/tictac/uploads/{}", api_base_url(), upload_id);
+
+    let (done, missing): (Vec<i32>, Vec<i32>) = (0..num_parts).partition(|&part| {
+        let (start, end) = part_range(part, file_size);
+        pending.is_range_completed(start, end)
+    });
+
+    // Progress is cumulative rather than "end of the last part", so it only ever
+    // moves forward while parts finish out of order
+    let mut bytes_uploaded: i64 = done
+        .iter()
+        .map(|&part| {
+            let (start, end) = part_range(part, file_size);
+            end - start + 1
+        })
+        .sum();
+    let mut parts_completed = done.len() as i32;
+
+    if parts_completed > 0 {
+        info!("{}/{} chunks already on server", parts_completed, num_parts);
+    }
+
+    // Parts already being sent when the file is stopped still finish, so the
+    // upload resumes from them next time
+    let mut uploads = stream::iter(missing)
+        .map(|part| {
+            let (ctx, upload_url, filename) = (&ctx, &upload_url, &filename);
+            async move {
+                if !control.proceed(device_id, filename).await {
+                    return Ok(None);
+                }
+                let Some(buffer) = read_part_for_upload(source, file, part, file_size, control, device_id).await? else {
+                    return Ok(None);
+                };
+                upload_part(ctx, upload_url, buffer, part, num_parts, file_size).await.map(Some)
+            }
+            // As a String, since a boxed error isn't Send and the loop below awaits
+            .map(|result: Result<_, Box<dyn std::error::Error>>| result.map_err(|e| e.to_string()))
+        })
+        .buffer_unordered(parallel_parts.max(1));
+
+    let mut stopped = false;
+    while let Some(result) = uploads.next().await {
+        let Some((start, end)) = result? else {
+            stopped = true;
+            continue;
+        };
+
+        // One row per part, rather than the whole record again
+        let (id, source_file) = (device_id.to_string(), file.clone());
+        if let Err(e) = write_state(move || mark_range_completed(&id, &source_file, start, end)).await {
+            eprintln!("Failed to save upload state: {}", e);
         }
+
+        bytes_uploaded += end - start + 1;
+        parts_completed += 1;
+
+        // Send progress update
+        events.send(UploadEvent::FileProgress {
+            device_id: device_id.to_string(),
+            filename: filename.clone(),
+            bytes_uploaded,
+            total_bytes: file_size,
+            parts_completed,
+            num_parts,
+        });
+    }
+
+    if stopped {
+        return Ok(UploadResult::Cancelled);
+    }
+
+    // Recorded so the next run skips it without asking the server
+    let (id, source_file, md5) = (device_id.to_string(), file.clone(), pending.md5.clone());
+    if let Err(e) = write_state(move || mark_upload_completed(&id, &source_file, md5)).await {
+        eprintln!("Failed to record completed upload: {}", e);
     }
 
     Ok(UploadResult::Completed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_fs::camera::FileStrategy;
+    use crate::camera_fs::file_source::MemorySource;
+    use crate::device_type::{test_devices, DeviceType};
+
+    fn camera(device_type: DeviceType, fs_strategy: FileStrategy) -> Camera {
+        Camera::new("Test".to_string(), device_type, "SERIAL".to_string(), fs_strategy, Vec::new())
+    }
+
+    #[test]
+    fn collects_insta360_recordings() {
+        let source = MemorySource::default()
+            .with_file("DCIM/Camera01/VID_20240101_120000_00_001.insv", vec![0; 16])
+            .with_file("DCIM/Camera01/LRV_20240101_120000_01_001.lrv", vec![0; 4])
+            .with_file("DCIM/fileinfo_list.list", vec![0; 4])
+            .with_file("MISC/log.txt", b"log".to_vec());
+
+        let files = collect_media_files(&source, &camera(test_devices::insta360_one_x2(), FileStrategy::Mount)).unwrap();
+        let found: Vec<(&str, &str)> = files.iter().map(|f| (f.file.name(), f.content_type)).collect();
+
+        assert_eq!(
+            found,
+            vec![
+                ("LRV_20240101_120000_01_001.lrv", "video/mp4"),
+                ("VID_20240101_120000_00_001.insv", "video/insv"),
+            ]
+        );
+    }
+
+    #[test]
+    fn collects_theta_z1_recordings() {
+        let source = MemorySource::default()
+            .with_file("DCIM/100RICOH/R0010001.JPG", vec![0; 16])
+            .with_file("DCIM/100RICOH/R0010001.DNG", vec![0; 16])
+            .with_file("DCIM/100RICOH/R0010002.MP4", vec![0; 16])
+            .with_file("DCIM/100RICOH/R0010002.THM", vec![0; 4])
+            .with_file("DCIM/R0010003.JPG", vec![0; 16]);
+
+        let files = collect_media_files(&source, &camera(test_devices::theta_z1(), FileStrategy::Mount)).unwrap();
+        let found: Vec<(&str, &str)> = files.iter().map(|f| (f.file.name(), f.content_type)).collect();
+
+        assert_eq!(
+            found,
+            vec![
+                ("R0010001.DNG", "image/x-adobe-dng"),
+                ("R0010001.JPG", "image/jpeg"),
+                ("R0010002.MP4", "video/mp4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn collects_folder_recordings_in_any_layout() {
+        let source = MemorySource::default()
+            .with_file("R0010001.JPG", vec![0; 16])
+            .with_file("site visit/R0010002.MP4", vec![0; 16])
+            .with_file("notes.txt", vec![0; 4]);
+
+        let files = collect_media_files(&source, &camera(test_devices::theta_z1(), FileStrategy::Folder)).unwrap();
+        let found: Vec<&str> = files.iter().map(|f| f.file.path.as_str()).collect();
+
+        assert_eq!(found, vec!["R0010001.JPG", "site visit/R0010002.MP4"]);
+    }
+
+    #[test]
+    fn hash_file_digests_every_part() {
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let source = MemorySource::default().with_file("DCIM/a.insv", content.clone());
+        let file = source.list_files().unwrap().remove(0);
+
+        let digest = hash_file(&source, &file, false, || false).unwrap().unwrap();
+
+        assert_eq!(digest.md5, format!("{:x}", md5::compute(&content)));
+        assert_eq!(digest.sha256, None);
+    }
+
+    #[test]
+    fn hashing_stops_between_blocks() {
+        let source = MemorySource::default().with_file("DCIM/a.insv", vec![1; CHUNK_SIZE as usize * 2]);
+        let file = source.list_files().unwrap().remove(0);
+        let blocks = std::cell::Cell::new(0);
+
+        let stopped = || {
+            blocks.set(blocks.get() + 1);
+            blocks.get() > 10
+        };
+        let digest = hash_file(&source, &file, false, stopped).unwrap();
+
+        assert_eq!(digest, None);
+        assert_eq!(blocks.get(), 11);
+    }
+
+    #[test]
+    fn read_part_reads_through_source() {
+        let content: Vec<u8> = (0..100).collect();
+        let source = MemorySource::default().with_file("a.insv", content.clone());
+        let file = source.list_files().unwrap().remove(0);
+
+        assert_eq!(read_part(&source, &file, 10, 19).unwrap(), content[10..20]);
+    }
+
+    #[test]
+    fn read_part_fails_when_file_shrank() {
+        let source = MemorySource::default().with_file("a.insv", vec![0; 10]);
+        let mut file = source.list_files().unwrap().remove(0);
+        file.size = 20;
+
+        let error = read_part(&source, &file, 0, 19).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parses_server_ranges() {
+        assert_eq!(parse_range_header("bytes=0-8388607"), vec![(0, 8388607)]);
+        assert_eq!(
+            parse_range_header(" bytes=0-8388607, 16777216-25165823 "),
+            vec![(0, 8388607), (16777216, 25165823)]
+        );
+    }
+
+    #[test]
+    fn drops_ranges_it_cannot_trust() {
+        // Open-ended, suffix, backwards, negative and garbage ranges
+        assert_eq!(parse_range_header("bytes=0-"), vec![]);
+        assert_eq!(parse_range_header("bytes=-500"), vec![]);
+        assert_eq!(parse_range_header("bytes=100-5"), vec![]);
+        assert_eq!(parse_range_header("bytes=5--3"), vec![]);
+        assert_eq!(parse_range_header("bytes=a-b,,"), vec![]);
+        assert_eq!(parse_range_header(""), vec![]);
+        // The good ones among them are kept
+        assert_eq!(parse_range_header("bytes=0-99,200-,300-399"), vec![(0, 99), (300, 399)]);
+    }
+
+    #[test]
+    fn cancelled_files_are_reported_without_uploading() {
+        let source: Arc<dyn FileSource> = Arc::new(MemorySource::default().with_file("DCIM/R0010001.JPG", vec![0; 16]));
+        let file = FileToUpload {
+            file: source.list_files().unwrap().remove(0),
+            source,
+            content_type: "image/jpeg",
+            device_id: "cam".to_string(),
+            capture_id: "R0010001".to_string(),
+        };
+        let control = UploadControl::default();
+        control.skip_file("cam", "R0010001.JPG");
+        let events = UploadEvents::default();
+        let ui = events.stream();
+        let log = events.stream();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (ui, log) = runtime.block_on(async move {
+            process_file(file, 1, SyncCheck::Local, &events, &control).await;
+            drop(events);
+            (ui.collect::<Vec<_>>().await, log.collect::<Vec<_>>().await)
+        });
+
+        // Every subscriber gets every event
+        for events in [ui, log] {
+            assert!(matches!(
+                events.as_slice(),
+                [UploadEvent::FileCancelled { device_id, filename }] if device_id == "cam" && filename == "R0010001.JPG"
+            ));
+        }
+    }
+
+    #[test]
+    fn slow_subscribers_miss_no_events() {
+        let events = UploadEvents::default();
+        let slow = events.stream();
+        // One that went away doesn't hold up the others
+        drop(events.subscribe());
+
+        for i in 0..5000 {
+            events.send(UploadEvent::FileSkipped {
+                device_id: "cam".to_string(),
+                filename: format!("R{:07}.JPG", i),
+            });
+        }
+        drop(events);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let received = runtime.block_on(slow.collect::<Vec<_>>());
+        assert_eq!(received.len(), 5000);
+        assert!(matches!(&received[4999], UploadEvent::FileSkipped { filename, .. } if filename == "R0004999.JPG"));
+    }
+}
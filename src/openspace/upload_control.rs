@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// How often a paused upload checks whether it may continue
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/**
+ * Lets whoever started an upload run cancel, pause or skip parts of it while
+ * it is in flight. The engine checks it before each file, between parts and
+ * while reading them, and waits before a retry end early, but parts already
+ * being sent finish first. Clones control the same run.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct UploadControl {
+    state: Arc<ControlState>,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    cancelled: AtomicBool,
+    paused: AtomicBool,
+    // (device ID, filename) of files to leave out of this run
+    skipped: Mutex<HashSet<(String, String)>>,
+    // Wakes `stopped` waiters on every cancel and skip
+    stopping: Notify,
+}
+
+impl UploadControl {
+    /// Stops every file; uploads already created keep their upload ID, so
+    /// the next run resumes them
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+        self.state.stopping.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Holds back new parts until `resume`
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Relaxed)
+    }
+
+    /// Stops one file, like `cancel` does for all of them
+    pub fn skip_file(&self, device_id: &str, filename: &str) {
+        self.skipped().insert((device_id.to_string(), filename.to_string()));
+        self.state.stopping.notify_waiters();
+    }
+
+    /// Whether the file is no longer to be uploaded in this run
+    pub fn is_stopped(&self, device_id: &str, filename: &str) -> bool {
+        self.is_cancelled() || self.skipped().contains(&(device_id.to_string(), filename.to_string()))
+    }
+
+    /// Waits out a pause, then tells whether the file may send its next part
+    pub async fn proceed(&self, device_id: &str, filename: &str) -> bool {
+        while self.is_paused() && !self.is_stopped(device_id, filename) {
+            tokio::time::sleep(PAUSE_CHECK_INTERVAL).await;
+        }
+
+        !self.is_stopped(device_id, filename)
+    }
+
+    /// Resolves once the file is stopped, for waits that should end then
+    pub async fn stopped(&self, device_id: &str, filename: &str) {
+        loop {
+            // Registered before checking, so a stop in between still wakes us
+            let mut notified = pin!(self.state.stopping.notified());
+            notified.as_mut().enable();
+            if self.is_stopped(device_id, filename) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn skipped(&self) -> std::sync::MutexGuard<'_, HashSet<(String, String)>> {
+        self.state.skipped.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_holds_files_until_resumed_or_stopped() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let control = UploadControl::default();
+        control.pause();
+
+        runtime.block_on(async {
+            let waiting = tokio::spawn({
+                let control = control.clone();
+                async move { control.proceed("cam", "a.jpg").await }
+            });
+            tokio::time::sleep(PAUSE_CHECK_INTERVAL * 2).await;
+            assert!(!waiting.is_finished());
+
+            control.resume();
+            assert!(waiting.await.unwrap());
+
+            control.pause();
+            control.skip_file("cam", "a.jpg");
+            assert!(!control.proceed("cam", "a.jpg").await);
+        });
+    }
+
+    #[test]
+    fn skip_stops_one_file_and_cancel_all() {
+        let control = UploadControl::default();
+
+        control.skip_file("cam", "a.jpg");
+        assert!(control.is_stopped("cam", "a.jpg"));
+        assert!(!control.is_stopped("cam", "b.jpg"));
+        assert!(!control.is_stopped("other", "a.jpg"));
+
+        control.cancel();
+        assert!(control.is_stopped("cam", "b.jpg"));
+    }
+
+    #[test]
+    fn stopped_wakes_on_skip_or_cancel() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let control = UploadControl::default();
+
+        runtime.block_on(async {
+            let waiting = |filename: &'static str| {
+                let control = control.clone();
+                tokio::spawn(async move { control.stopped("cam", filename).await })
+            };
+            let (a, b) = (waiting("a.jpg"), waiting("b.jpg"));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!a.is_finished() && !b.is_finished());
+
+            control.skip_file("cam", "a.jpg");
+            a.await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(!b.is_finished());
+
+            control.cancel();
+            b.await.unwrap();
+        });
+    }
+}
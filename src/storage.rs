@@ -1,29 +1,623 @@
+use crate::camera_fs::file_source::SourceFile;
+use crate::config::{settings, Environment};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::backup::Progress;
+use rusqlite::{params, Connection, DatabaseName, ErrorCode, OptionalExtension, Row, TransactionBehavior};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::fs;
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const STORAGE_DIR: &str = ".openspace_sync";
-const SKIPPED_FILES_FILE: &str = "skipped_files.json";
+const SYNC_STATE_DB: &str = "sync_state.db";
+const SYNC_STATE_BACKUP: &str = "sync_state.db.bak";
+const SYNC_LOCK_FILE: &str = "sync.lock";
+// Files the server already had, as earlier versions kept them; imported into
+// the database on first start
+const LEGACY_SKIPPED_FILES_FILE: &str = "skipped_files.json";
 
-#[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
-pub struct SkippedFile {
+// How long a change waits for another instance to finish writing before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+// How old sync_state.db.bak gets before it is taken again
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// MIGRATIONS[n] upgrades a version n database (its `user_version`) to version n + 1
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE files (
+        device_id TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        modified INTEGER,
+        md5 TEXT,
+        upload_id TEXT,
+        state TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (device_id, filename)
+    );
+    CREATE TABLE completed_parts (
+        device_id TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        start INTEGER NOT NULL,
+        end INTEGER NOT NULL,
+        PRIMARY KEY (device_id, filename, start),
+        FOREIGN KEY (device_id, filename) REFERENCES files (device_id, filename) ON DELETE CASCADE
+    );
+"];
+
+static SYNC_STATE: LazyLock<Result<Mutex<SyncState>, String>> = LazyLock::new(|| {
+    let dir = get_storage_dir().and_then(|storage_dir| {
+        let dir = get_environment_dir()?;
+        move_skipped_files(&storage_dir, &dir)?;
+        Ok(dir)
+    });
+    dir.and_then(|dir| SyncState::open(&dir)).map(Mutex::new).map_err(|e| {
+        eprintln!("Failed to open sync state: {}", e);
+        e.to_string()
+    })
+});
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    // Created on the server, parts still being sent
+    Uploading,
+    // Every part was sent and acknowledged
+    Uploaded,
+    // The server already had it
+    Skipped,
+}
+
+impl FileState {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileState::Uploading => "uploading",
+            FileState::Uploaded => "uploaded",
+            FileState::Skipped => "skipped",
+        }
+    }
+}
+
+impl ToSql for FileState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for FileState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "uploading" => Ok(FileState::Uploading),
+            "uploaded" => Ok(FileState::Uploaded),
+            "skipped" => Ok(FileState::Skipped),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// What we know about one file of one device
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileRecord {
+    pub device_id: String,
     pub filename: String,
+    // Relative to the card root; just the filename for rows imported from skipped_files.json
+    pub path: String,
     pub size: i64,
-    pub device_id: String,
+    pub modified: Option<u64>,
+    // Whole-file MD5, lowercase hex
+    pub md5: Option<String>,
+    pub upload_id: Option<String>,
+    // Byte ranges (inclusive) the server has acknowledged so far
+    pub completed_parts: Vec<(i64, i64)>,
+    pub state: FileState,
+    // Seconds since the Unix epoch
+    pub created_at: u64,
+    pub updated_at: u64,
 }
 
-impl SkippedFile {
-    pub fn new(filename: String, size: i64, device_id: String) -> Self {
+impl FileRecord {
+    pub fn new(device_id: &str, file: &SourceFile, state: FileState) -> Self {
+        let now = unix_now();
         Self {
-            filename,
-            size,
-            device_id,
+            device_id: device_id.to_string(),
+            filename: file.name().to_string(),
+            path: file.path.clone(),
+            size: file.size as i64,
+            modified: file.modified,
+            md5: None,
+            upload_id: None,
+            completed_parts: Vec::new(),
+            state,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// An upload that was created on the server but not finished yet
+    pub fn uploading(device_id: &str, file: &SourceFile, upload_id: String, md5: Option<String>) -> Self {
+        Self {
+            upload_id: Some(upload_id),
+            md5,
+            ..Self::new(device_id, file, FileState::Uploading)
         }
     }
+
+    /// Records an acknowledged byte range, merging it with touching ranges so the
+    /// list stays short even for files with thousands of parts.
+    pub fn mark_range_completed(&mut self, start: i64, end: i64) {
+        self.completed_parts.push((start, end));
+        self.completed_parts.sort();
+
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(self.completed_parts.len());
+        for &(s, e) in &self.completed_parts {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 + 1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.completed_parts = merged;
+    }
+
+    pub fn is_range_completed(&self, start: i64, end: i64) -> bool {
+        self.completed_parts.iter().any(|&(s, e)| s <= start && end <= e)
+    }
+
+    /// Same file as listed on the camera now. The modification time is only
+    /// compared when both sides have one.
+    fn matches(&self, file: &SourceFile) -> bool {
+        self.size == file.size as i64
+            && match (self.modified, file.modified) {
+                (Some(recorded), Some(current)) => recorded == current,
+                _ => true,
+            }
+    }
+}
+
+/// A row of the skipped_files.json of earlier versions
+#[derive(Debug, Deserialize)]
+struct SkippedFile {
+    filename: String,
+    size: i64,
+    device_id: String,
+}
+
+/**
+ * The sync state database at ~/.openspace_sync/<environment>/sync_state.db:
+ * one row per file, keyed by device and filename (the same identity the server
+ * uses), and one row per byte range the server acknowledged. Each change is a
+ * transaction of its own, so finishing a part only writes that part's row.
+ * Nothing is cached, so every instance sees what the others wrote. A copy is
+ * kept in sync_state.db.bak to fall back on should the database get corrupted.
+*/
+struct SyncState {
+    conn: Connection,
+    dir: PathBuf,
 }
 
-fn get_storage_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+impl SyncState {
+    fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dir.join(SYNC_STATE_DB);
+
+        let conn = match open_database(&path) {
+            Ok(conn) => conn,
+            Err(e) if matches!(e.sqlite_error_code(), Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt)) => {
+                let moved_to = quarantine(&path)?;
+                eprintln!("{} is corrupt ({}), moved it to {}", SYNC_STATE_DB, e, moved_to.display());
+                let mut conn = open_database(&path)?;
+                restore_backup(&mut conn, &dir.join(SYNC_STATE_BACKUP));
+                conn
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut state = Self {
+            conn,
+            dir: dir.to_path_buf(),
+        };
+        if state.migrate()? == 0 {
+            state.import_skipped_files(dir)?;
+        }
+
+        Ok(state)
+    }
+
+    /// Brings the schema up to date and returns the version the database had
+    fn migrate(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
+        // Taken before reading the version, so two instances starting at once
+        // don't both create the tables
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let from_version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if from_version > MIGRATIONS.len() {
+            return Err(format!(
+                "{} has schema version {}, this app only knows up to {}",
+                SYNC_STATE_DB,
+                from_version,
+                MIGRATIONS.len()
+            )
+            .into());
+        }
+
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(from_version) {
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", version + 1)?;
+        }
+        tx.commit()?;
+
+        if from_version < MIGRATIONS.len() {
+            info!("Upgraded sync state from version {} to {}", from_version, MIGRATIONS.len());
+        }
+
+        Ok(from_version)
+    }
+
+    /// Moves the skipped_files.json of earlier versions into the new database
+    fn import_skipped_files(&mut self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let path = dir.join(LEGACY_SKIPPED_FILES_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let records = read_skipped_files(&path)?;
+
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for record in &records {
+            write_record(&tx, record)?;
+        }
+        tx.commit()?;
+
+        if !records.is_empty() {
+            info!("Imported {} files into {}", records.len(), SYNC_STATE_DB);
+        }
+        if let Err(e) = fs::remove_file(&path) {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+        }
+
+        Ok(())
+    }
+
+    /// Copies the database to sync_state.db.bak once the copy there is older
+    /// than BACKUP_INTERVAL, through SQLite so the copy is never half a write
+    fn backup_if_due(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let backup = self.dir.join(SYNC_STATE_BACKUP);
+        let age = fs::metadata(&backup).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+        if age.is_some_and(|age| age < BACKUP_INTERVAL) {
+            return Ok(());
+        }
+
+        let temp_path = temp_path_for(&backup);
+        self.conn.backup(DatabaseName::Main, &temp_path, None)?;
+        commit_temp_file(&temp_path, &backup)
+    }
+
+    fn get(&self, device_id: &str, file: &SourceFile) -> Result<Option<FileRecord>, Box<dyn std::error::Error>> {
+        find_record(&self.conn, device_id, file)
+    }
+
+    fn records(&self) -> Result<Vec<FileRecord>, Box<dyn std::error::Error>> {
+        let mut statement = self.conn.prepare("SELECT * FROM files ORDER BY device_id, filename")?;
+        let records = statement.query_map([], read_record)?.collect::<Result<Vec<_>, _>>()?;
+
+        records.into_iter().map(|r| with_completed_parts(&self.conn, r)).collect()
+    }
+
+    /// Inserts or replaces the row of the same file, along with its ranges
+    fn upsert(&mut self, record: &FileRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        write_record(&tx, record)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Adds one acknowledged byte range to an upload in progress
+    fn add_completed_range(
+        &self,
+        device_id: &str,
+        file: &SourceFile,
+        start: i64,
+        end: i64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let added = self.conn.execute(
+            "INSERT OR REPLACE INTO completed_parts (device_id, filename, start, end)
+             SELECT device_id, filename, ?3, ?4 FROM files WHERE device_id = ?1 AND filename = ?2",
+            params![device_id, file.name(), start, end],
+        )?;
+        if added == 0 {
+            return Err(format!("No upload recorded for {}", file.name()).into());
+        }
+
+        Ok(())
+    }
+
+    fn mark_upload_completed(
+        &mut self,
+        device_id: &str,
+        file: &SourceFile,
+        md5: Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Read and written under one write lock, so another instance can't
+        // change the row in between
+        let tx = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut record =
+            find_record(&tx, device_id, file)?.unwrap_or_else(|| FileRecord::new(device_id, file, FileState::Uploaded));
+        record.state = FileState::Uploaded;
+        record.completed_parts = Vec::new();
+        if md5.is_some() {
+            record.md5 = md5;
+        }
+        write_record(&tx, &record)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn remove(&self, device_id: &str, file: &SourceFile) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "DELETE FROM files WHERE device_id = ?1 AND filename = ?2",
+            params![device_id, file.name()],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets which files are on the server, keeping unfinished uploads
+    fn clear_synced(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.conn.execute(
+            "DELETE FROM files WHERE state <> ?1",
+            params![FileState::Uploading],
+        )?;
+        Ok(())
+    }
+}
+
+fn open_database(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    // Another instance may be writing; wait for it rather than fail
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Readers don't wait for writers, and a commit is a single append to the log
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    // Every commit is on disk before it returns, so a power cut loses nothing
+    // that was reported as done
+    conn.pragma_update(None, "synchronous", "FULL")?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+
+    Ok(conn)
+}
+
+/// Replaces a file's row and its ranges, keeping when it was first recorded
+fn write_record(conn: &Connection, record: &FileRecord) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO files (device_id, filename, path, size, modified, md5, upload_id, state, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (device_id, filename) DO UPDATE SET
+             path = excluded.path, size = excluded.size, modified = excluded.modified, md5 = excluded.md5,
+             upload_id = excluded.upload_id, state = excluded.state, updated_at = excluded.updated_at",
+        params![
+            record.device_id,
+            record.filename,
+            record.path,
+            record.size,
+            record.modified,
+            record.md5,
+            record.upload_id,
+            record.state,
+            record.created_at,
+            unix_now(),
+        ],
+    )?;
+
+    conn.execute(
+        "DELETE FROM completed_parts WHERE device_id = ?1 AND filename = ?2",
+        params![record.device_id, record.filename],
+    )?;
+    for (start, end) in &record.completed_parts {
+        conn.execute(
+            "INSERT INTO completed_parts (device_id, filename, start, end) VALUES (?1, ?2, ?3, ?4)",
+            params![record.device_id, record.filename, start, end],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The row of the file, if it is still the same file, with its ranges
+fn find_record(
+    conn: &Connection,
+    device_id: &str,
+    file: &SourceFile,
+) -> Result<Option<FileRecord>, Box<dyn std::error::Error>> {
+    let record = conn
+        .query_row(
+            "SELECT * FROM files WHERE device_id = ?1 AND filename = ?2",
+            params![device_id, file.name()],
+            read_record,
+        )
+        .optional()?;
+
+    match record {
+        Some(record) if record.matches(file) => Ok(Some(with_completed_parts(conn, record)?)),
+        _ => Ok(None),
+    }
+}
+
+fn with_completed_parts(conn: &Connection, mut record: FileRecord) -> Result<FileRecord, Box<dyn std::error::Error>> {
+    let mut statement =
+        conn.prepare_cached("SELECT start, end FROM completed_parts WHERE device_id = ?1 AND filename = ?2")?;
+    let ranges = statement.query_map(params![record.device_id, record.filename], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    for range in ranges {
+        let (start, end) = range?;
+        record.mark_range_completed(start, end);
+    }
+
+    Ok(record)
+}
+
+// Ranges are read separately, see with_completed_parts
+fn read_record(row: &Row) -> rusqlite::Result<FileRecord> {
+    Ok(FileRecord {
+        device_id: row.get("device_id")?,
+        filename: row.get("filename")?,
+        path: row.get("path")?,
+        size: row.get("size")?,
+        modified: row.get("modified")?,
+        md5: row.get("md5")?,
+        upload_id: row.get("upload_id")?,
+        completed_parts: Vec::new(),
+        state: row.get("state")?,
+        created_at: row.get("created_at")?,
+        updated_at: row.get("updated_at")?,
+    })
+}
+
+fn sync_state() -> Result<MutexGuard<'static, SyncState>, Box<dyn std::error::Error>> {
+    let state = SYNC_STATE.as_ref().map_err(|e| format!("Sync state is unavailable: {}", e))?;
+    // A panic mid-update leaves the database consistent, so keep using it
+    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = state.backup_if_due() {
+        eprintln!("Failed to back up sync state: {}", e);
+    }
+
+    Ok(state)
+}
+
+/// Reads that fail are reported and treated as "nothing recorded"
+fn lookup(device_id: &str, file: &SourceFile) -> Option<FileRecord> {
+    sync_state().and_then(|state| state.get(device_id, file)).unwrap_or_else(|e| {
+        eprintln!("Failed to read sync state: {}", e);
+        None
+    })
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The rows of an earlier version's skipped_files.json. A path wasn't kept, so
+/// the filename stands in for it.
+fn read_skipped_files(path: &Path) -> Result<Vec<FileRecord>, Box<dyn std::error::Error>> {
+    let skipped: Vec<SkippedFile> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let now = unix_now();
+
+    Ok(skipped
+        .into_iter()
+        .map(|file| FileRecord {
+            device_id: file.device_id,
+            path: file.filename.clone(),
+            filename: file.filename,
+            size: file.size,
+            modified: None,
+            md5: None,
+            upload_id: None,
+            completed_parts: Vec::new(),
+            state: FileState::Skipped,
+            created_at: now,
+            updated_at: now,
+        })
+        .collect())
+}
+
+// Keeps a corrupt database around for inspection instead of overwriting it
+fn quarantine(path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let moved_to = path.with_extension(format!("db.corrupt-{}", unix_now()));
+    fs::rename(path, &moved_to)?;
+    // Its log can't be replayed into a new database
+    for suffix in ["-wal", "-shm"] {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(suffix);
+        let _ = fs::remove_file(PathBuf::from(name));
+    }
+
+    Ok(moved_to)
+}
+
+/// Fills the empty database that replaced a corrupt one from the last backup.
+/// Without a usable backup it stays empty, and files are checked with the
+/// server again.
+fn restore_backup(conn: &mut Connection, backup: &Path) {
+    if !backup.exists() {
+        return;
+    }
+
+    match conn.restore(DatabaseName::Main, backup, None::<fn(Progress)>) {
+        Ok(()) => info!("Restored {} from {}", SYNC_STATE_DB, backup.display()),
+        Err(e) => eprintln!("Failed to restore {} ({}), starting with an empty sync state", backup.display(), e),
+    }
+}
+
+/**
+ * Held for as long as a sync runs, see [`lock_sync_run`]. The lock goes away
+ * when this is dropped, or with the process should it die.
+*/
+pub struct SyncLock {
+    _file: File,
+}
+
+/// Makes sure only one instance of the app syncs an environment at a time, so
+/// two don't upload the same files at once
+pub fn lock_sync_run() -> Result<SyncLock, Box<dyn std::error::Error>> {
+    lock_file(&get_environment_dir()?.join(SYNC_LOCK_FILE))
+}
+
+fn lock_file(path: &Path) -> Result<SyncLock, Box<dyn std::error::Error>> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(SyncLock { _file: file }),
+        Err(TryLockError::WouldBlock) => Err("Another OpenSpace Sync is already syncing".into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Writes next to the target, flushes it to disk and renames it over the
+/// target, so a crash leaves either the old or the new file, never half of one
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_path = temp_path_for(path);
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+
+    commit_temp_file(&temp_path, path)
+}
+
+/// Where [`write_atomic`] writes before renaming, e.g. credentials.enc.tmp
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Renames a temp file that was already flushed over `path`, and persists the
+/// rename itself
+pub fn commit_temp_file(temp_path: &Path, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    fs::rename(temp_path, path)?;
+
+    // Directories can't be opened like this on Windows
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Earlier versions kept skipped_files.json in the storage dir itself, shared
+/// by every environment. It moves into the first environment started since,
+/// and is imported into its database from there.
+fn move_skipped_files(storage_dir: &Path, environment_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let shared = storage_dir.join(LEGACY_SKIPPED_FILES_FILE);
+    if shared.exists() && !environment_dir.join(LEGACY_SKIPPED_FILES_FILE).exists() {
+        fs::rename(&shared, environment_dir.join(LEGACY_SKIPPED_FILES_FILE))?;
+        info!("Moved {} to {}", LEGACY_SKIPPED_FILES_FILE, environment_dir.display());
+    }
+
+    Ok(())
+}
+
+pub fn get_storage_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
     let home = dirs::home_dir().ok_or("Could not find home directory")?;
     let storage_dir = home.join(STORAGE_DIR);
 
@@ -31,55 +625,314 @@ fn get_storage_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
         fs::create_dir_all(&storage_dir)?;
     }
 
-    Ok(storage_dir.join(SKIPPED_FILES_FILE))
+    Ok(storage_dir)
 }
 
-pub fn load_skipped_files() -> Result<HashSet<SkippedFile>, Box<dyn std::error::Error>> {
-    let storage_path = get_storage_path()?;
+/// ~/.openspace_sync/<environment> of the active environment, for what belongs
+/// to one backend: its credentials and sync state. Files uploaded to staging
+/// aren't on prod, and tokens of one aren't valid on the other.
+pub fn get_environment_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    environment_dir(settings().environment)
+}
 
-    if !storage_path.exists() {
-        return Ok(HashSet::new());
+pub fn environment_dir(environment: Environment) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = get_storage_dir()?.join(environment.to_string());
+
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
     }
 
-    let content = fs::read_to_string(storage_path)?;
-    let skipped: HashSet<SkippedFile> = serde_json::from_str(&content)?;
+    Ok(dir)
+}
 
-    Ok(skipped)
+/// Every file record, sorted by device and filename
+pub fn file_records() -> Vec<FileRecord> {
+    sync_state().and_then(|state| state.records()).unwrap_or_else(|e| {
+        eprintln!("Failed to read sync state: {}", e);
+        Vec::new()
+    })
 }
 
-pub fn save_skipped_files(skipped: &HashSet<SkippedFile>) -> Result<(), Box<dyn std::error::Error>> {
-    let storage_path = get_storage_path()?;
-    let content = serde_json::to_string_pretty(skipped)?;
+/// What is recorded for the file, if it is still the same file
+pub fn file_state(device_id: &str, file: &SourceFile) -> Option<FileState> {
+    lookup(device_id, file).map(|r| r.state)
+}
 
-    fs::write(storage_path, content)?;
+/// Whether the file is known to be on the server already
+pub fn is_file_synced(device_id: &str, file: &SourceFile) -> bool {
+    lookup(device_id, file).is_some_and(|r| matches!(r.state, FileState::Uploaded | FileState::Skipped))
+}
 
-    Ok(())
+/// The record of a file we uploaded ourselves, if it is still the same file
+pub fn find_uploaded_file(device_id: &str, file: &SourceFile) -> Option<FileRecord> {
+    lookup(device_id, file).filter(|r| r.state == FileState::Uploaded)
 }
 
-pub fn add_skipped_file(skipped_file: SkippedFile) -> Result<(), Box<dyn std::error::Error>> {
-    let mut skipped = load_skipped_files()?;
-    skipped.insert(skipped_file);
-    save_skipped_files(&skipped)?;
+/// Marks the upload finished, keeping its upload ID. `md5` is the whole-file
+/// digest the upload was created with.
+pub fn mark_upload_completed(
+    device_id: &str,
+    file: &SourceFile,
+    md5: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.mark_upload_completed(device_id, file, md5)
+}
 
-    Ok(())
+pub fn mark_file_skipped(device_id: &str, file: &SourceFile) -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.upsert(&FileRecord::new(device_id, file, FileState::Skipped))
 }
 
-pub fn clear_skipped_files() -> Result<(), Box<dyn std::error::Error>> {
-    let storage_path = get_storage_path()?;
+/// Forgets which files are on the server; unfinished uploads are kept so they can resume
+pub fn clear_synced_files() -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.clear_synced()
+}
 
-    if storage_path.exists() {
-        fs::remove_file(storage_path)?;
-    }
+pub fn find_pending_upload(device_id: &str, file: &SourceFile) -> Option<FileRecord> {
+    lookup(device_id, file).filter(|r| r.state == FileState::Uploading && r.upload_id.is_some())
+}
 
-    Ok(())
+/// Inserts or replaces the record for the same file
+pub fn save_pending_upload(upload: &FileRecord) -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.upsert(upload)
+}
+
+/// Records a byte range (inclusive) the server acknowledged for an upload
+/// saved with [`save_pending_upload`]
+pub fn mark_range_completed(
+    device_id: &str,
+    file: &SourceFile,
+    start: i64,
+    end: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.add_completed_range(device_id, file, start, end)
 }
 
-pub fn is_file_skipped(filename: &str, size: i64, device_id: &str) -> bool {
-    match load_skipped_files() {
-        Ok(skipped) => {
-            let file = SkippedFile::new(filename.to_string(), size, device_id.to_string());
-            skipped.contains(&file)
+/// Drops whatever is recorded for the file, so it is treated as never seen
+pub fn forget_file(device_id: &str, file: &SourceFile) -> Result<(), Box<dyn std::error::Error>> {
+    sync_state()?.remove(device_id, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn source_file(path: &str, size: u64) -> SourceFile {
+        SourceFile {
+            path: path.to_string(),
+            size,
+            modified: Some(1_700_000_000),
         }
-        Err(_) => false,
+    }
+
+    fn open(dir: &TempDir) -> SyncState {
+        SyncState::open(dir.path()).unwrap()
+    }
+
+    #[test]
+    fn imports_skipped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(LEGACY_SKIPPED_FILES_FILE),
+            r#"[{"filename": "R0010001.JPG", "size": 10, "device_id": "z1"}]"#,
+        )
+        .unwrap();
+
+        let state = open(&dir);
+
+        let skipped = state.get("z1", &source_file("DCIM/100RICOH/R0010001.JPG", 10)).unwrap().unwrap();
+        assert_eq!(skipped.state, FileState::Skipped);
+        assert!(!dir.path().join(LEGACY_SKIPPED_FILES_FILE).exists());
+        assert!(dir.path().join(SYNC_STATE_DB).exists());
+    }
+
+    #[test]
+    fn round_trips_and_detects_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = source_file("DCIM/Camera01/VID_1.insv", 100);
+
+        let mut state = open(&dir);
+        state.upsert(&FileRecord::uploading("x2", &file, "up-2".to_string(), None)).unwrap();
+        drop(state);
+
+        let state = open(&dir);
+        assert!(state.get("x2", &file).unwrap().is_some());
+        // Rewritten on the card since
+        let changed = SourceFile {
+            modified: Some(1_800_000_000),
+            ..file.clone()
+        };
+        assert!(state.get("x2", &changed).unwrap().is_none());
+        assert!(state.get("x2", &source_file("DCIM/Camera01/VID_1.insv", 101)).unwrap().is_none());
+    }
+
+    #[test]
+    fn records_completed_parts_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = source_file("DCIM/Camera01/VID_5.insv", 30);
+
+        let mut state = open(&dir);
+        assert!(state.add_completed_range("x2", &file, 0, 9).is_err());
+        state.upsert(&FileRecord::uploading("x2", &file, "up-5".to_string(), None)).unwrap();
+        state.add_completed_range("x2", &file, 10, 19).unwrap();
+        state.add_completed_range("x2", &file, 0, 9).unwrap();
+        drop(state);
+
+        let pending = open(&dir).get("x2", &file).unwrap().unwrap();
+        assert_eq!(pending.completed_parts, vec![(0, 19)]);
+    }
+
+    #[test]
+    fn completed_uploads_count_as_synced() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = source_file("DCIM/Camera01/VID_2.insv", 100);
+
+        let mut state = open(&dir);
+        let mut record = FileRecord::uploading("x2", &file, "up-3".to_string(), None);
+        record.mark_range_completed(0, 99);
+        state.upsert(&record).unwrap();
+        state.mark_upload_completed("x2", &file, Some("abc".to_string())).unwrap();
+        drop(state);
+
+        let uploaded = open(&dir).get("x2", &file).unwrap().unwrap();
+        assert_eq!(uploaded.state, FileState::Uploaded);
+        assert_eq!(uploaded.upload_id.as_deref(), Some("up-3"));
+        assert_eq!(uploaded.md5.as_deref(), Some("abc"));
+        assert!(uploaded.completed_parts.is_empty());
+    }
+
+    #[test]
+    fn clearing_keeps_unfinished_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let (skipped, pending) = (source_file("a.insv", 10), source_file("b.insv", 10));
+
+        let mut state = open(&dir);
+        state.upsert(&FileRecord::new("x2", &skipped, FileState::Skipped)).unwrap();
+        state.upsert(&FileRecord::uploading("x2", &pending, "up-6".to_string(), None)).unwrap();
+        state.clear_synced().unwrap();
+
+        let names: Vec<String> = state.records().unwrap().into_iter().map(|r| r.filename).collect();
+        assert_eq!(names, vec!["b.insv"]);
+    }
+
+    #[test]
+    fn instances_share_every_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first_file, second_file) = (source_file("a.insv", 10), source_file("b.insv", 10));
+        let mut first = open(&dir);
+        let mut second = open(&dir);
+
+        first.upsert(&FileRecord::new("x2", &first_file, FileState::Skipped)).unwrap();
+        second.upsert(&FileRecord::uploading("x2", &second_file, "up-7".to_string(), None)).unwrap();
+        second.mark_upload_completed("x2", &second_file, Some("abc".to_string())).unwrap();
+
+        assert_eq!(first.get("x2", &second_file).unwrap().map(|r| r.state), Some(FileState::Uploaded));
+        assert_eq!(second.get("x2", &first_file).unwrap().map(|r| r.state), Some(FileState::Skipped));
+
+        // Clearing in one clears what the other wrote
+        first.clear_synced().unwrap();
+        assert!(second.records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn writes_wait_for_another_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = source_file("a.insv", 10);
+        let mut first = open(&dir);
+        let mut second = open(&dir);
+
+        let tx = first.conn.transaction_with_behavior(TransactionBehavior::Immediate).unwrap();
+        let writer = std::thread::spawn(move || {
+            second.upsert(&FileRecord::new("x2", &file, FileState::Skipped)).unwrap();
+            second
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!writer.is_finished());
+        tx.commit().unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(first.records().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn moves_a_corrupt_database_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(SYNC_STATE_DB), vec![0x42; 4096]).unwrap();
+
+        let state = open(&dir);
+
+        assert!(state.records().unwrap().is_empty());
+        let quarantined = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(Result::ok)
+            .any(|e| e.file_name().to_string_lossy().starts_with("sync_state.db.corrupt-"));
+        assert!(quarantined);
+    }
+
+    #[test]
+    fn restores_a_corrupt_database_from_its_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = source_file("DCIM/Camera01/VID_4.insv", 100);
+
+        let mut state = open(&dir);
+        state.upsert(&FileRecord::new("x2", &file, FileState::Skipped)).unwrap();
+        state.backup_if_due().unwrap();
+        // Newer than the backup, so it is lost with the database
+        let newer = source_file("DCIM/Camera01/VID_5.insv", 100);
+        state.upsert(&FileRecord::new("x2", &newer, FileState::Skipped)).unwrap();
+        drop(state);
+        fs::write(dir.path().join(SYNC_STATE_DB), vec![0x42; 4096]).unwrap();
+
+        let state = open(&dir);
+
+        let records = state.records().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].filename, "VID_4.insv");
+    }
+
+    #[test]
+    fn takes_a_backup_only_once_it_is_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = open(&dir);
+        let backup = dir.path().join(SYNC_STATE_BACKUP);
+
+        state.backup_if_due().unwrap();
+        let taken_at = fs::metadata(&backup).unwrap().modified().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        state.backup_if_due().unwrap();
+
+        assert_eq!(fs::metadata(&backup).unwrap().modified().unwrap(), taken_at);
+    }
+
+    #[test]
+    fn only_one_sync_runs_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SYNC_LOCK_FILE);
+
+        let lock = lock_file(&path).unwrap();
+        assert!(lock_file(&path).is_err());
+        drop(lock);
+        assert!(lock_file(&path).is_ok());
+    }
+
+    #[test]
+    fn rejects_newer_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open(dir.path().join(SYNC_STATE_DB)).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        drop(conn);
+
+        assert!(SyncState::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn merges_completed_ranges() {
+        let mut record = FileRecord::new("x2", &source_file("a.insv", 30), FileState::Uploading);
+        record.mark_range_completed(10, 19);
+        record.mark_range_completed(0, 9);
+
+        assert_eq!(record.completed_parts, vec![(0, 19)]);
+        assert!(record.is_range_completed(5, 15));
+        assert!(!record.is_range_completed(15, 25));
     }
 }